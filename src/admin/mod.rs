@@ -1,3 +1 @@
-use crate::{debug_println, sctx};
-
 pub mod users;
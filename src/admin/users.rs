@@ -1,6 +1,8 @@
-use sqlx::{mysql, types::chrono, MySql, Pool};
+use sqlx::{MySql, Pool};
 
-use crate::{debug_println, sctx};
+use crate::error::Result;
+use crate::sctx;
+use crate::secret::Secret;
 
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct User {
@@ -12,15 +14,12 @@ pub struct User {
     pub last_name: Option<String>,
     pub consented: bool,
     pub organization_id: i32,
-    pub jwt_secret: String,
-    pub salt: String,
+    pub jwt_secret: Secret<String>,
+    pub salt: Secret<String>,
 }
 
-pub async fn find_user_by_email(
-    sctx: &mut sctx::SecurityContext,
-    email: &str,
-) -> Result<User, sqlx::Error> {
-    sqlx::query_as::<_, User>(
+pub async fn find_user_by_email(sctx: &mut sctx::SecurityContext, email: &str) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(
         "SELECT
 	u.id,
 	u.username,
@@ -38,14 +37,12 @@ WHERE d.email = ?",
     )
     .bind(email)
     .fetch_one(&sctx.pool)
-    .await
+    .await?;
+    Ok(user)
 }
 
-pub async fn find_user_by_username(
-    pool: &Pool<MySql>,
-    username: &str,
-) -> Result<User, sqlx::Error> {
-    sqlx::query_as::<_, User>(
+pub async fn find_user_by_username(pool: &Pool<MySql>, username: &str) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(
         "SELECT
 	u.id,
 	u.username,
@@ -64,5 +61,6 @@ WHERE u.username = ?",
     )
     .bind(username)
     .fetch_one(pool)
-    .await
+    .await?;
+    Ok(user)
 }
@@ -1,60 +1,172 @@
 use crate::debug_println;
-use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::secret::Secret;
+use crate::token::ProxyToken;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MirandaConfig {
     pub host: String,
+    #[serde(deserialize_with = "deserialize_port")]
     pub port: String,
     pub user: String,
-    pub password: String,
+    pub password: Secret<String>,
     pub database: String,
     pub max_pool_connections: Option<u32>,
+    pub min_pool_connections: Option<u32>,
+    pub acquire_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_lifetime_secs: Option<u64>,
+    pub ping_after_idle_secs: Option<u64>,
+    pub test_before_acquire: Option<bool>,
+    pub statement_cache_capacity: Option<usize>,
+    /// One of DISABLED, PREFERRED, REQUIRED, VERIFY_CA or VERIFY_IDENTITY.
+    pub ssl_mode: Option<String>,
+    pub ssl_ca: Option<String>,
+    pub ssl_cert: Option<String>,
+    pub ssl_key: Option<String>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct PartialMirandaConfig {
     pub host: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_port")]
     pub port: Option<String>,
     pub user: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Secret<String>>,
     pub database: Option<String>,
     pub max_pool_connections: Option<u32>,
+    pub min_pool_connections: Option<u32>,
+    pub acquire_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_lifetime_secs: Option<u64>,
+    pub ping_after_idle_secs: Option<u64>,
+    pub test_before_acquire: Option<bool>,
+    pub statement_cache_capacity: Option<usize>,
+    /// One of DISABLED, PREFERRED, REQUIRED, VERIFY_CA or VERIFY_IDENTITY.
+    pub ssl_mode: Option<String>,
+    pub ssl_ca: Option<String>,
+    pub ssl_cert: Option<String>,
+    pub ssl_key: Option<String>,
 }
 
 impl MirandaConfig {
-    pub fn new_from_file(path: &str) -> Result<MirandaConfig, Box<dyn std::error::Error>> {
-        let config = std::fs::read_to_string(path)?;
-        Ok(serde_json::from_str(&config)?)
+    /// Reads a JSON, TOML or YAML file, chosen by the file extension. Files with
+    /// any other extension are read as JSON.
+    pub fn new_from_file(path: &str) -> Result<MirandaConfig> {
+        parse_file(path)
+    }
+
+    /// Reads the profile `profile` from a profiles file, see [`ConfigProfiles`].
+    /// Without `profile` the `MIRANDA_PROFILE` environment variable selects it.
+    pub fn new_from_profile(path: &str, profile: Option<&str>) -> Result<MirandaConfig> {
+        ConfigProfiles::new_from_file(path)?.select(profile)
     }
 
-    pub fn new_from_default() -> Result<MirandaConfig, Box<dyn std::error::Error>> {
+    pub fn new_from_default() -> Result<MirandaConfig> {
         if let Ok(env_config) = std::env::var("MIRANDA_CONFIG_JSON") {
             debug_println!("[cfg] Loading config from MIRANDA_CONFIG_JSON");
-            return Ok(serde_json::from_str(&env_config)?);
+            return parse(&env_config, ConfigFormat::Json, "MIRANDA_CONFIG_JSON");
         }
 
         // check if config.json exists in home directory
-        let home_dir = dirs::home_dir().unwrap();
-        let home_config = home_dir.join("config.json");
-        if home_config.exists() {
-            debug_println!("[cfg] Loading config from {}", home_config.display());
-            let config = std::fs::read_to_string(home_config)?;
-            return Ok(serde_json::from_str(&config)?);
+        match dirs::home_dir() {
+            Some(home_dir) => {
+                let home_config = home_dir.join("config.json");
+                if home_config.exists() {
+                    debug_println!("[cfg] Loading config from {}", home_config.display());
+                    return MirandaConfig::new_from_file(&home_config.to_string_lossy());
+                }
+            }
+            None => {
+                debug_println!("[cfg] No home directory, skipping ~/config.json");
+            }
         }
 
         // check if config.json exists in /etc/miranda
         let etc_config = std::path::Path::new("/etc/miranda/config.json");
         if etc_config.exists() {
             debug_println!("[cfg] Loading config from {}", etc_config.display());
-            let config = std::fs::read_to_string(etc_config)?;
-            return Ok(serde_json::from_str(&config)?);
+            return MirandaConfig::new_from_file(&etc_config.to_string_lossy());
         }
 
         // return error if config.json does not exist
         debug_println!("[cfg] config.json not found");
-        Err("config.json not found".into())
+        Err(Error::Config("config.json not found".to_string()))
     }
 
-    pub fn merge_into_new(&mut self, other: PartialMirandaConfig) -> Result<MirandaConfig, ()> {
+    /// Checks the values that can be checked without connecting and returns every
+    /// problem found as [`Error::InvalidConfig`].
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.host.trim().is_empty() {
+            problems.push("host: must not be empty".to_string());
+        }
+        match self.port.parse::<u16>() {
+            Ok(0) | Err(_) => problems.push(format!(
+                "port: must be a number between 1 and 65535, got {:?}",
+                self.port
+            )),
+            Ok(_) => {}
+        }
+        if self.user.trim().is_empty() {
+            problems.push("user: must not be empty".to_string());
+        }
+        if self.database.trim().is_empty() {
+            problems.push("database: must not be empty".to_string());
+        }
+        if let Some(name) = ProxyToken::account_name(&self.user) {
+            // proxy accounts come from a pxy.<username>.<password> token
+            if !ProxyToken::is_valid_part(name) {
+                problems.push(format!(
+                    "user: proxy account {:?} must be pxy. followed by letters and digits",
+                    self.user
+                ));
+            }
+            if !ProxyToken::is_valid_password(self.password.expose()) {
+                problems.push(
+                    "password: proxy account password must be non-empty and contain no '.'"
+                        .to_string(),
+                );
+            }
+        }
+        let max = self.max_pool_connections.unwrap_or(1);
+        if max == 0 {
+            problems.push("max_pool_connections: must be at least 1".to_string());
+        }
+        if let Some(min) = self.min_pool_connections {
+            if min > max {
+                problems.push(format!(
+                    "min_pool_connections: {} exceeds max_pool_connections {}",
+                    min, max
+                ));
+            }
+        }
+        if self.acquire_timeout_secs == Some(0) {
+            problems.push("acquire_timeout_secs: must be at least 1".to_string());
+        }
+        if let Some(mode) = &self.ssl_mode {
+            if sqlx::mysql::MySqlSslMode::from_str(mode).is_err() {
+                problems.push(format!(
+                    "ssl_mode: {:?} is not one of DISABLED, PREFERRED, REQUIRED, VERIFY_CA, VERIFY_IDENTITY",
+                    mode
+                ));
+            }
+        }
+        if self.ssl_cert.is_some() != self.ssl_key.is_some() {
+            problems.push("ssl_cert, ssl_key: must be set together".to_string());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(problems))
+        }
+    }
+
+    pub fn merge_into_new(&mut self, other: PartialMirandaConfig) -> Result<MirandaConfig> {
         let mut new_config = self.clone();
         if let Some(host) = other.host {
             new_config.host = host;
@@ -74,50 +186,211 @@ impl MirandaConfig {
         if let Some(max_pool_connections) = other.max_pool_connections {
             new_config.max_pool_connections = Some(max_pool_connections);
         }
+        if let Some(min_pool_connections) = other.min_pool_connections {
+            new_config.min_pool_connections = Some(min_pool_connections);
+        }
+        if let Some(acquire_timeout_secs) = other.acquire_timeout_secs {
+            new_config.acquire_timeout_secs = Some(acquire_timeout_secs);
+        }
+        if let Some(idle_timeout_secs) = other.idle_timeout_secs {
+            new_config.idle_timeout_secs = Some(idle_timeout_secs);
+        }
+        if let Some(max_lifetime_secs) = other.max_lifetime_secs {
+            new_config.max_lifetime_secs = Some(max_lifetime_secs);
+        }
+        if let Some(ping_after_idle_secs) = other.ping_after_idle_secs {
+            new_config.ping_after_idle_secs = Some(ping_after_idle_secs);
+        }
+        if let Some(test_before_acquire) = other.test_before_acquire {
+            new_config.test_before_acquire = Some(test_before_acquire);
+        }
+        if let Some(statement_cache_capacity) = other.statement_cache_capacity {
+            new_config.statement_cache_capacity = Some(statement_cache_capacity);
+        }
+        if let Some(ssl_mode) = other.ssl_mode {
+            new_config.ssl_mode = Some(ssl_mode);
+        }
+        if let Some(ssl_ca) = other.ssl_ca {
+            new_config.ssl_ca = Some(ssl_ca);
+        }
+        if let Some(ssl_cert) = other.ssl_cert {
+            new_config.ssl_cert = Some(ssl_cert);
+        }
+        if let Some(ssl_key) = other.ssl_key {
+            new_config.ssl_key = Some(ssl_key);
+        }
         Ok(new_config)
     }
 }
 
 impl PartialMirandaConfig {
     pub fn new() -> PartialMirandaConfig {
-        PartialMirandaConfig {
-            host: None,
-            port: None,
-            user: None,
-            password: None,
-            database: None,
-            max_pool_connections: None,
-        }
+        PartialMirandaConfig::default()
     }
 
     pub fn new_from_user(user: String, password: String) -> PartialMirandaConfig {
         PartialMirandaConfig {
             user: Some(user),
-            password: Some(password),
-            host: None,
-            port: None,
-            database: None,
-            max_pool_connections: None,
+            password: Some(Secret::new(password)),
+            ..PartialMirandaConfig::default()
         }
     }
 
-    pub fn new_from_token_string(
-        token: String,
-    ) -> Result<PartialMirandaConfig, Box<dyn std::error::Error>> {
-        // pxy.username.password
-        let parts: Vec<&str> = token.split('.').collect();
-        if parts.len() != 3 {
-            return Err("Invalid token".into());
+    /// Reads the `MIRANDA_<FIELD>` environment variables, e.g. `MIRANDA_HOST` or
+    /// `MIRANDA_MAX_POOL_CONNECTIONS`. A proxy token in `MIRANDA_TOKEN` or the file
+    /// named by `MIRANDA_TOKEN_FILE` sets user and password and takes precedence
+    /// over `MIRANDA_USER` and `MIRANDA_PASSWORD`.
+    pub fn new_from_env() -> Result<PartialMirandaConfig> {
+        PartialMirandaConfig::new_from_env_with(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`PartialMirandaConfig::new_from_env`], reading variables through `var`.
+    pub fn new_from_env_with(var: impl Fn(&str) -> Option<String>) -> Result<PartialMirandaConfig> {
+        fn parse<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<Option<T>>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .map(|v| {
+                    v.parse::<T>()
+                        .map_err(|e| Error::Config(format!("Invalid {} {:?}: {}", name, v, e)))
+                })
+                .transpose()
         }
-        let user = "pxy.".to_owned() + parts[1];
-        let password = parts[2].to_string();
-        Ok(PartialMirandaConfig::new_from_user(user, password))
+        let mut config = PartialMirandaConfig {
+            host: var("MIRANDA_HOST"),
+            port: var("MIRANDA_PORT"),
+            user: var("MIRANDA_USER"),
+            password: var("MIRANDA_PASSWORD").map(Secret::new),
+            database: var("MIRANDA_DATABASE"),
+            max_pool_connections: parse(
+                "MIRANDA_MAX_POOL_CONNECTIONS",
+                var("MIRANDA_MAX_POOL_CONNECTIONS"),
+            )?,
+            min_pool_connections: parse(
+                "MIRANDA_MIN_POOL_CONNECTIONS",
+                var("MIRANDA_MIN_POOL_CONNECTIONS"),
+            )?,
+            acquire_timeout_secs: parse(
+                "MIRANDA_ACQUIRE_TIMEOUT_SECS",
+                var("MIRANDA_ACQUIRE_TIMEOUT_SECS"),
+            )?,
+            idle_timeout_secs: parse(
+                "MIRANDA_IDLE_TIMEOUT_SECS",
+                var("MIRANDA_IDLE_TIMEOUT_SECS"),
+            )?,
+            max_lifetime_secs: parse(
+                "MIRANDA_MAX_LIFETIME_SECS",
+                var("MIRANDA_MAX_LIFETIME_SECS"),
+            )?,
+            ping_after_idle_secs: parse(
+                "MIRANDA_PING_AFTER_IDLE_SECS",
+                var("MIRANDA_PING_AFTER_IDLE_SECS"),
+            )?,
+            test_before_acquire: parse(
+                "MIRANDA_TEST_BEFORE_ACQUIRE",
+                var("MIRANDA_TEST_BEFORE_ACQUIRE"),
+            )?,
+            statement_cache_capacity: parse(
+                "MIRANDA_STATEMENT_CACHE_CAPACITY",
+                var("MIRANDA_STATEMENT_CACHE_CAPACITY"),
+            )?,
+            ssl_mode: var("MIRANDA_SSL_MODE"),
+            ssl_ca: var("MIRANDA_SSL_CA"),
+            ssl_cert: var("MIRANDA_SSL_CERT"),
+            ssl_key: var("MIRANDA_SSL_KEY"),
+        };
+        if let Some(token) = ProxyToken::new_from_env_with(var)? {
+            config = config.merge_into_new(PartialMirandaConfig::new_from_token(&token))?;
+        }
+        Ok(config)
     }
 
-    pub fn merge_into_new(
-        &mut self,
-        other: PartialMirandaConfig,
-    ) -> Result<PartialMirandaConfig, ()> {
+    pub fn new_from_file(path: &str) -> Result<PartialMirandaConfig> {
+        parse_file(path)
+    }
+
+    /// The names of the fields that are set.
+    pub fn set_fields(&self) -> Vec<&'static str> {
+        let fields = [
+            ("host", self.host.is_some()),
+            ("port", self.port.is_some()),
+            ("user", self.user.is_some()),
+            ("password", self.password.is_some()),
+            ("database", self.database.is_some()),
+            ("max_pool_connections", self.max_pool_connections.is_some()),
+            ("min_pool_connections", self.min_pool_connections.is_some()),
+            ("acquire_timeout_secs", self.acquire_timeout_secs.is_some()),
+            ("idle_timeout_secs", self.idle_timeout_secs.is_some()),
+            ("max_lifetime_secs", self.max_lifetime_secs.is_some()),
+            ("ping_after_idle_secs", self.ping_after_idle_secs.is_some()),
+            ("test_before_acquire", self.test_before_acquire.is_some()),
+            (
+                "statement_cache_capacity",
+                self.statement_cache_capacity.is_some(),
+            ),
+            ("ssl_mode", self.ssl_mode.is_some()),
+            ("ssl_ca", self.ssl_ca.is_some()),
+            ("ssl_cert", self.ssl_cert.is_some()),
+            ("ssl_key", self.ssl_key.is_some()),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Converts to a [`MirandaConfig`], failing if a required field is not set.
+    pub fn into_config(self) -> Result<MirandaConfig> {
+        let set = self.set_fields();
+        let missing: Vec<&str> = ["host", "port", "user", "password", "database"]
+            .into_iter()
+            .filter(|field| !set.contains(field))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Config(format!(
+                "Missing required config fields: {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(MirandaConfig {
+            host: self.host.unwrap_or_default(),
+            port: self.port.unwrap_or_default(),
+            user: self.user.unwrap_or_default(),
+            password: self.password.unwrap_or_default(),
+            database: self.database.unwrap_or_default(),
+            max_pool_connections: self.max_pool_connections,
+            min_pool_connections: self.min_pool_connections,
+            acquire_timeout_secs: self.acquire_timeout_secs,
+            idle_timeout_secs: self.idle_timeout_secs,
+            max_lifetime_secs: self.max_lifetime_secs,
+            ping_after_idle_secs: self.ping_after_idle_secs,
+            test_before_acquire: self.test_before_acquire,
+            statement_cache_capacity: self.statement_cache_capacity,
+            ssl_mode: self.ssl_mode,
+            ssl_ca: self.ssl_ca,
+            ssl_cert: self.ssl_cert,
+            ssl_key: self.ssl_key,
+        })
+    }
+
+    pub fn new_from_token(token: &ProxyToken) -> PartialMirandaConfig {
+        PartialMirandaConfig {
+            user: Some(token.username()),
+            password: Some(token.password().clone()),
+            ..PartialMirandaConfig::default()
+        }
+    }
+
+    pub fn new_from_token_string(token: String) -> Result<PartialMirandaConfig> {
+        let token = Secret::new(token);
+        Ok(PartialMirandaConfig::new_from_token(
+            &token.expose().parse()?,
+        ))
+    }
+
+    pub fn merge_into_new(&mut self, other: PartialMirandaConfig) -> Result<PartialMirandaConfig> {
         let mut new_config = self.clone();
         if let Some(host) = other.host {
             new_config.host = Some(host);
@@ -137,6 +410,312 @@ impl PartialMirandaConfig {
         if let Some(max_pool_connections) = other.max_pool_connections {
             new_config.max_pool_connections = Some(max_pool_connections);
         }
+        if let Some(min_pool_connections) = other.min_pool_connections {
+            new_config.min_pool_connections = Some(min_pool_connections);
+        }
+        if let Some(acquire_timeout_secs) = other.acquire_timeout_secs {
+            new_config.acquire_timeout_secs = Some(acquire_timeout_secs);
+        }
+        if let Some(idle_timeout_secs) = other.idle_timeout_secs {
+            new_config.idle_timeout_secs = Some(idle_timeout_secs);
+        }
+        if let Some(max_lifetime_secs) = other.max_lifetime_secs {
+            new_config.max_lifetime_secs = Some(max_lifetime_secs);
+        }
+        if let Some(ping_after_idle_secs) = other.ping_after_idle_secs {
+            new_config.ping_after_idle_secs = Some(ping_after_idle_secs);
+        }
+        if let Some(test_before_acquire) = other.test_before_acquire {
+            new_config.test_before_acquire = Some(test_before_acquire);
+        }
+        if let Some(statement_cache_capacity) = other.statement_cache_capacity {
+            new_config.statement_cache_capacity = Some(statement_cache_capacity);
+        }
+        if let Some(ssl_mode) = other.ssl_mode {
+            new_config.ssl_mode = Some(ssl_mode);
+        }
+        if let Some(ssl_ca) = other.ssl_ca {
+            new_config.ssl_ca = Some(ssl_ca);
+        }
+        if let Some(ssl_cert) = other.ssl_cert {
+            new_config.ssl_cert = Some(ssl_cert);
+        }
+        if let Some(ssl_key) = other.ssl_key {
+            new_config.ssl_key = Some(ssl_key);
+        }
         Ok(new_config)
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn of(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+pub(crate) fn parse<T: DeserializeOwned>(
+    config: &str,
+    format: ConfigFormat,
+    source: &str,
+) -> Result<T> {
+    let result = match format {
+        ConfigFormat::Json => serde_json::from_str(config).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::from_str(config).map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_yaml::from_str(config).map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| Error::Config(format!("Error parsing {}: {}", source, e)))
+}
+
+fn parse_file<T: DeserializeOwned>(path: &str) -> Result<T> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Error reading {}: {}", path, e)))?;
+    parse(&config, ConfigFormat::of(Path::new(path)), path)
+}
+
+// TOML and YAML users write `port = 3306`, JSON configs have always used a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Port {
+    Number(u64),
+    String(String),
+}
+
+impl From<Port> for String {
+    fn from(port: Port) -> String {
+        match port {
+            Port::Number(port) => port.to_string(),
+            Port::String(port) => port,
+        }
+    }
+}
+
+fn deserialize_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<String, D::Error> {
+    Port::deserialize(deserializer).map(String::from)
+}
+
+fn deserialize_optional_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    Ok(Option::<Port>::deserialize(deserializer)?.map(String::from))
+}
+
+/// Named configurations for several environments in one file. Every profile is
+/// merged over the optional `default` section with [`PartialMirandaConfig::merge_into_new`]:
+///
+/// ```toml
+/// [default]
+/// port = 3306
+/// database = "miranda"
+///
+/// [profiles.dev]
+/// host = "localhost"
+/// user = "dev"
+/// password = "dev"
+///
+/// [profiles.prod]
+/// host = "db.prod.internal"
+/// ssl_mode = "VERIFY_IDENTITY"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ConfigProfiles {
+    #[serde(default)]
+    pub default: PartialMirandaConfig,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, PartialMirandaConfig>,
+}
+
+impl ConfigProfiles {
+    pub fn new_from_file(path: &str) -> Result<ConfigProfiles> {
+        parse_file(path)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// The merged settings of profile `name`, before checking required fields.
+    pub fn partial(&self, name: &str) -> Result<PartialMirandaConfig> {
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            Error::Config(format!(
+                "Unknown profile {:?}, expected one of: {}",
+                name,
+                self.names().join(", ")
+            ))
+        })?;
+        self.default.clone().merge_into_new(profile.clone())
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<MirandaConfig> {
+        debug_println!("[cfg] Using profile {}", name);
+        self.partial(name)?.into_config()
+    }
+
+    /// Resolves `profile`, or the profile named by `MIRANDA_PROFILE` if it is None.
+    pub fn select(&self, profile: Option<&str>) -> Result<MirandaConfig> {
+        match profile {
+            Some(name) => self.resolve(name),
+            None => match std::env::var("MIRANDA_PROFILE") {
+                Ok(name) => self.resolve(&name),
+                Err(_) => Err(Error::Config(format!(
+                    "No profile selected, set MIRANDA_PROFILE to one of: {}",
+                    self.names().join(", ")
+                ))),
+            },
+        }
+    }
+}
+
+/// A layer of the configuration assembled by [`ConfigLoader`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    SystemFile(PathBuf),
+    UserFile(PathBuf),
+    ExplicitFile(PathBuf),
+    /// The `MIRANDA_CONFIG_JSON` environment variable.
+    EnvJson,
+    /// The per-field `MIRANDA_<FIELD>` environment variables.
+    Env,
+    Programmatic,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::SystemFile(path) => write!(f, "system file {}", path.display()),
+            ConfigSource::UserFile(path) => write!(f, "user file {}", path.display()),
+            ConfigSource::ExplicitFile(path) => write!(f, "file {}", path.display()),
+            ConfigSource::EnvJson => write!(f, "MIRANDA_CONFIG_JSON"),
+            ConfigSource::Env => write!(f, "environment"),
+            ConfigSource::Programmatic => write!(f, "program"),
+        }
+    }
+}
+
+/// Which layer set each field of a config returned by [`ConfigLoader::load`].
+#[derive(Clone, Debug, Default)]
+pub struct ConfigReport {
+    pub sources: BTreeMap<&'static str, ConfigSource>,
+}
+
+impl ConfigReport {
+    pub fn source_of(&self, field: &str) -> Option<&ConfigSource> {
+        self.sources.get(field)
+    }
+}
+
+/// Assembles a [`MirandaConfig`] from layers, each overriding the fields set by the
+/// ones before it: the system file, the user file, an explicit file,
+/// `MIRANDA_CONFIG_JSON`, the `MIRANDA_<FIELD>` environment variables and finally
+/// values set in code. Missing system and user files are skipped.
+#[derive(Clone, Debug)]
+pub struct ConfigLoader {
+    system_file: Option<PathBuf>,
+    user_file: Option<PathBuf>,
+    explicit_file: Option<PathBuf>,
+    env: bool,
+    overrides: PartialMirandaConfig,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        ConfigLoader {
+            system_file: Some(PathBuf::from("/etc/miranda/config.json")),
+            user_file: dirs::home_dir().map(|home| home.join("config.json")),
+            explicit_file: None,
+            env: true,
+            overrides: PartialMirandaConfig::default(),
+        }
+    }
+}
+
+impl ConfigLoader {
+    pub fn new() -> ConfigLoader {
+        ConfigLoader::default()
+    }
+
+    pub fn system_file(mut self, path: Option<PathBuf>) -> Self {
+        self.system_file = path;
+        self
+    }
+
+    pub fn user_file(mut self, path: Option<PathBuf>) -> Self {
+        self.user_file = path;
+        self
+    }
+
+    /// A file that must exist.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.explicit_file = Some(path.into());
+        self
+    }
+
+    /// Whether to read `MIRANDA_CONFIG_JSON` and the `MIRANDA_<FIELD>` variables.
+    pub fn env(mut self, env: bool) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub fn with(mut self, overrides: PartialMirandaConfig) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    pub fn load(&self) -> Result<(MirandaConfig, ConfigReport)> {
+        let mut layers = Vec::new();
+        if let Some(path) = &self.system_file {
+            if path.exists() {
+                let layer = PartialMirandaConfig::new_from_file(&path.to_string_lossy())?;
+                layers.push((ConfigSource::SystemFile(path.clone()), layer));
+            }
+        }
+        if let Some(path) = &self.user_file {
+            if path.exists() {
+                let layer = PartialMirandaConfig::new_from_file(&path.to_string_lossy())?;
+                layers.push((ConfigSource::UserFile(path.clone()), layer));
+            }
+        }
+        if let Some(path) = &self.explicit_file {
+            let layer = PartialMirandaConfig::new_from_file(&path.to_string_lossy())?;
+            layers.push((ConfigSource::ExplicitFile(path.clone()), layer));
+        }
+        if self.env {
+            if let Ok(json) = std::env::var("MIRANDA_CONFIG_JSON") {
+                let layer = serde_json::from_str(&json).map_err(|e| {
+                    Error::Config(format!("Error parsing MIRANDA_CONFIG_JSON: {}", e))
+                })?;
+                layers.push((ConfigSource::EnvJson, layer));
+            }
+            layers.push((ConfigSource::Env, PartialMirandaConfig::new_from_env()?));
+        }
+        layers.push((ConfigSource::Programmatic, self.overrides.clone()));
+        ConfigLoader::merge_layers(layers)
+    }
+
+    pub(crate) fn merge_layers(
+        layers: Vec<(ConfigSource, PartialMirandaConfig)>,
+    ) -> Result<(MirandaConfig, ConfigReport)> {
+        let mut merged = PartialMirandaConfig::default();
+        let mut report = ConfigReport::default();
+        for (source, layer) in layers {
+            for field in layer.set_fields() {
+                debug_println!("[cfg] {} set by {}", field, source);
+                report.sources.insert(field, source.clone());
+            }
+            merged = merged.merge_into_new(layer)?;
+        }
+        Ok((merged.into_config()?, report))
+    }
+}
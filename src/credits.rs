@@ -0,0 +1,283 @@
+use crate::debug_println;
+use crate::error::{Error, Result};
+use crate::orm::{decode_column, BigDecimal};
+use crate::sctx::SecurityExecutor;
+use bigdecimal::Signed;
+use sqlx::mysql::{MySqlDatabaseError, MySqlRow};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::Row;
+use std::future::Future;
+
+/// One row of the user's credit ledger. Negative amounts are debits.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LedgerEntry {
+    pub id: i32,
+    pub amount: BigDecimal,
+    pub statement: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl LedgerEntry {
+    fn try_from_row(row: MySqlRow) -> Result<LedgerEntry> {
+        Ok(LedgerEntry {
+            id: decode_column(&row, "id")?,
+            amount: decode_column(&row, "amount")?,
+            statement: decode_column(&row, "statement")?,
+            created_at: decode_column(&row, "created_at")?,
+        })
+    }
+
+    pub fn is_debit(&self) -> bool {
+        self.amount.is_negative()
+    }
+}
+
+/// Selects a page of ledger entries, newest first, optionally bounded in time
+/// (`since` inclusive, `until` exclusive).
+#[derive(Clone, Debug)]
+pub struct LedgerRange {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+impl Default for LedgerRange {
+    fn default() -> Self {
+        LedgerRange {
+            since: None,
+            until: None,
+            offset: 0,
+            limit: 100,
+        }
+    }
+}
+
+impl LedgerRange {
+    /// The range for the page following this one.
+    pub fn next_page(&self) -> LedgerRange {
+        LedgerRange {
+            offset: self.offset + self.limit,
+            ..self.clone()
+        }
+    }
+}
+
+/// Returns the current credit balance of the context's user, including holds.
+pub async fn balance(sctx: &mut impl SecurityExecutor) -> Result<BigDecimal> {
+    let mut conn = sctx.connection().await?;
+    let balance: Option<BigDecimal> = sqlx::query_scalar("SELECT balance FROM v_credit_balance")
+        .fetch_optional(&mut *conn)
+        .await?;
+    balance.ok_or_else(|| Error::NotFound("No credit balance found".to_string()))
+}
+
+pub async fn ledger(
+    sctx: &mut impl SecurityExecutor,
+    range: &LedgerRange,
+) -> Result<Vec<LedgerEntry>> {
+    let mut query = String::from("SELECT * FROM v_credit_transaction WHERE 1 = 1");
+    if range.since.is_some() {
+        query.push_str(" AND created_at >= ?");
+    }
+    if range.until.is_some() {
+        query.push_str(" AND created_at < ?");
+    }
+    query.push_str(" ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?");
+    debug_println!("Query: {}", query);
+
+    let mut q = sqlx::query(&query);
+    if let Some(since) = range.since {
+        q = q.bind(since);
+    }
+    if let Some(until) = range.until {
+        q = q.bind(until);
+    }
+    let mut conn = sctx.connection().await?;
+    let rows = q
+        .bind(range.limit)
+        .bind(range.offset)
+        .fetch_all(&mut *conn)
+        .await?;
+    rows.into_iter().map(LedgerEntry::try_from_row).collect()
+}
+
+/// Credits reserved up front, e.g. for a long running DockerJob. A hold must be
+/// settled with [`CreditHold::capture`] or [`CreditHold::release`]; if that fails
+/// the hold is still there to settle again.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CreditHold {
+    pub id: i32,
+    pub amount: BigDecimal,
+}
+
+/// Reserves `amount` credits. Fails if the user's available balance is too low.
+pub async fn hold(
+    sctx: &mut impl SecurityExecutor,
+    amount: BigDecimal,
+    statement: &str,
+) -> Result<CreditHold> {
+    if !amount.is_positive() {
+        return Err(Error::InvalidArgument(format!(
+            "Hold amount must be positive, got {}",
+            amount
+        )));
+    }
+    let mut conn = sctx.connection().await?;
+    let row = sqlx::query("CALL sp_hold_credits (?, ?)")
+        .bind(&amount)
+        .bind(statement)
+        .fetch_optional(&mut *conn)
+        .await?;
+    // sqlx does not support getting by column name from rows returned by stored procedures
+    let id = match row {
+        Some(row) => row.try_get::<i32, _>(0)?,
+        None => {
+            return Err(Error::NotFound(
+                "sp_hold_credits did not return a hold id".to_string(),
+            ))
+        }
+    };
+    debug_println!("[credits] Holding {} as hold {}", amount, id);
+    Ok(CreditHold { id, amount })
+}
+
+impl CreditHold {
+    /// Debits `amount` (at most the held amount) and returns the rest of the hold.
+    pub async fn capture(
+        &self,
+        sctx: &mut impl SecurityExecutor,
+        amount: BigDecimal,
+        statement: &str,
+    ) -> Result<()> {
+        if !amount.is_positive() {
+            return Err(Error::InvalidArgument(format!(
+                "Capture amount must be positive, got {}",
+                amount
+            )));
+        }
+        if amount > self.amount {
+            return Err(Error::InvalidArgument(format!(
+                "Cannot capture {} from hold {} of {}",
+                amount, self.id, self.amount
+            )));
+        }
+        let mut conn = sctx.connection().await?;
+        sqlx::query("CALL sp_capture_credits (?, ?, ?)")
+            .bind(self.id)
+            .bind(amount)
+            .bind(statement)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the whole hold to the user's balance.
+    pub async fn release(&self, sctx: &mut impl SecurityExecutor) -> Result<()> {
+        let mut conn = sctx.connection().await?;
+        sqlx::query("CALL sp_release_credits (?)")
+            .bind(self.id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Storage used by [`transact_idempotent`]. Implemented for every
+/// [`SecurityExecutor`]; other implementations allow testing without MySQL.
+pub trait CreditBackend: Send {
+    /// Returns the ledger entry recorded under `key`, if any.
+    fn find_by_key(
+        &mut self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<LedgerEntry>>> + Send;
+
+    /// Records a transaction under `key`, returning None if the key is already taken.
+    fn transact_with_key(
+        &mut self,
+        key: &str,
+        amount: &BigDecimal,
+        statement: &str,
+    ) -> impl Future<Output = Result<Option<LedgerEntry>>> + Send;
+}
+
+/// MySQL error number for a duplicate entry on a unique key.
+const ER_DUP_ENTRY: u16 = 1062;
+
+impl<E: SecurityExecutor> CreditBackend for E {
+    async fn find_by_key(&mut self, key: &str) -> Result<Option<LedgerEntry>> {
+        let mut conn = self.connection().await?;
+        let row = sqlx::query("SELECT * FROM v_credit_transaction WHERE idempotency_key = ?")
+            .bind(key)
+            .fetch_optional(&mut *conn)
+            .await?;
+        row.map(LedgerEntry::try_from_row).transpose()
+    }
+
+    async fn transact_with_key(
+        &mut self,
+        key: &str,
+        amount: &BigDecimal,
+        statement: &str,
+    ) -> Result<Option<LedgerEntry>> {
+        let mut conn = self.connection().await?;
+        let result = sqlx::query("CALL sp_transact_credits_with_key (?, ?, ?)")
+            .bind(key)
+            .bind(amount)
+            .bind(statement)
+            .execute(&mut *conn)
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(sqlx::Error::Database(e))
+                if e.try_downcast_ref::<MySqlDatabaseError>()
+                    .map(|e| e.number() == ER_DUP_ENTRY)
+                    .unwrap_or(false) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        drop(conn);
+        self.find_by_key(key).await
+    }
+}
+
+/// Like [`crate::orm::transact_credits`], but safe to retry: the transaction is
+/// recorded under the caller supplied `key` and a replay with the same key returns
+/// the original ledger entry instead of charging again. Reusing a key for a
+/// different amount or statement fails with [`Error::Conflict`].
+pub async fn transact_idempotent(
+    backend: &mut impl CreditBackend,
+    key: &str,
+    amount: BigDecimal,
+    statement: &str,
+) -> Result<LedgerEntry> {
+    if key.is_empty() {
+        return Err(Error::InvalidArgument(
+            "Idempotency key must not be empty".to_string(),
+        ));
+    }
+    let entry = match backend.find_by_key(key).await? {
+        Some(entry) => entry,
+        None => match backend.transact_with_key(key, &amount, statement).await? {
+            Some(entry) => return Ok(entry),
+            // another attempt with the same key won the race, replay its result
+            None => backend.find_by_key(key).await?.ok_or_else(|| {
+                Error::NotFound(format!("No transaction recorded for key {}", key))
+            })?,
+        },
+    };
+    debug_println!(
+        "[credits] Replaying transaction {} for key {}",
+        entry.id,
+        key
+    );
+    if entry.amount != amount || entry.statement != statement {
+        return Err(Error::Conflict(format!(
+            "Idempotency key {} was used for transaction {} of {}",
+            key, entry.id, entry.amount
+        )));
+    }
+    Ok(entry)
+}
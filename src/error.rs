@@ -0,0 +1,119 @@
+use sqlx::mysql::MySqlDatabaseError;
+use std::fmt;
+
+/// MySQL server error numbers that indicate the statement can safely be retried.
+/// 1205: lock wait timeout exceeded, 1213: deadlock found when trying to get lock.
+const TRANSIENT_MYSQL_ERRORS: [u16; 2] = [1205, 1213];
+
+/// Crate-wide error type returned by every public fallible function.
+#[derive(Debug)]
+pub enum Error {
+    /// The requested row or object does not exist (or is not visible to this context).
+    NotFound(String),
+    /// The security context is not allowed to perform the operation.
+    PermissionDenied(String),
+    /// The token was well formed but its expiry time has passed.
+    TokenExpired,
+    /// The token could not be parsed, decrypted or did not match its user.
+    TokenMalformed(String),
+    /// The configuration is missing, unreadable or invalid.
+    Config(String),
+    /// The configuration was read but failed validation, one message per problem.
+    InvalidConfig(Vec<String>),
+    /// The row was modified by someone else since it was read.
+    Conflict(String),
+    /// A workflow state change that the state machine does not allow.
+    InvalidTransition { from: String, to: String },
+    /// A caller supplied argument was rejected before reaching the database.
+    InvalidArgument(String),
+    /// A resource limit was reached, e.g. the connection cap of a `PoolManager`.
+    Exhausted(String),
+    /// A column could not be decoded into the Rust type of its field, usually
+    /// because of schema drift or a NULL in a non-Option column.
+    Decode {
+        column: String,
+        sql_type: String,
+        source: sqlx::Error,
+    },
+    /// A database error that is not expected to go away by retrying.
+    Database(sqlx::Error),
+    /// A database error that is likely to succeed when retried (lost connection,
+    /// pool timeout, deadlock, lock wait timeout).
+    Transient(sqlx::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Returns true if the operation that produced this error may succeed when retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Transient(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(what) => write!(f, "not found: {}", what),
+            Error::PermissionDenied(why) => write!(f, "permission denied: {}", why),
+            Error::TokenExpired => write!(f, "token has expired"),
+            Error::TokenMalformed(why) => write!(f, "malformed token: {}", why),
+            Error::Config(why) => write!(f, "configuration error: {}", why),
+            Error::InvalidConfig(problems) => {
+                write!(f, "invalid configuration: {}", problems.join("; "))
+            }
+            Error::Conflict(why) => write!(f, "conflict: {}", why),
+            Error::InvalidTransition { from, to } => {
+                write!(f, "invalid workflow transition: {} -> {}", from, to)
+            }
+            Error::InvalidArgument(why) => write!(f, "invalid argument: {}", why),
+            Error::Exhausted(why) => write!(f, "resource exhausted: {}", why),
+            Error::Decode {
+                column,
+                sql_type,
+                source,
+            } => write!(
+                f,
+                "error decoding column {} ({}): {}",
+                column, sql_type, source
+            ),
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Transient(e) => write!(f, "transient database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(e) | Error::Transient(e) => Some(e),
+            Error::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Returns true if a sqlx error is worth retrying.
+pub(crate) fn is_transient_sqlx_error(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(db) => db
+            .try_downcast_ref::<MySqlDatabaseError>()
+            .map(|e| TRANSIENT_MYSQL_ERRORS.contains(&e.number()))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        if let sqlx::Error::RowNotFound = e {
+            return Error::NotFound("No row found".to_string());
+        }
+        if is_transient_sqlx_error(&e) {
+            Error::Transient(e)
+        } else {
+            Error::Database(e)
+        }
+    }
+}
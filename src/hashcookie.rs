@@ -1,3 +1,5 @@
+use crate::error::{Error, Result};
+use crate::secret::Secret;
 use crate::{admin::users::User, debug_println};
 use base64::{engine::general_purpose, Engine as _};
 use chacha20::cipher::{KeyIvInit, StreamCipher};
@@ -10,7 +12,7 @@ use sha2::Sha256;
 pub struct HashCookieToken {
     pub exp: i64,
     pub username: String,
-    pub dbauth: Option<String>,
+    pub dbauth: Option<Secret<String>>,
 }
 
 #[derive(Debug)]
@@ -22,7 +24,7 @@ pub struct HashCookieTokenPayload {
 }
 
 impl HashCookieTokenPayload {
-    pub fn new(token: String) -> Result<HashCookieTokenPayload, Box<dyn std::error::Error>> {
+    pub fn new(token: String) -> Result<HashCookieTokenPayload> {
         // exp.b64(username).b64(payload).b64(signature)
         if token.starts_with('"') && token.ends_with('"') {
             let token = token[1..token.len() - 1].to_string();
@@ -30,19 +32,18 @@ impl HashCookieTokenPayload {
         }
         let parts: Vec<&str> = token.split('.').collect();
         if parts.len() < 3 {
-            return Err("Invalid token".into());
+            return Err(Error::TokenMalformed("Invalid token".to_string()));
         }
         let exp = match parts[0].parse::<i64>() {
             Ok(exp) => exp,
             Err(e) => {
                 debug_println!("Error parsing exp: {}", e);
-                return Err("Invalid token".into());
+                return Err(Error::TokenMalformed("Invalid token".to_string()));
             }
         };
-        let username = String::from_utf8(general_purpose::URL_SAFE.decode(parts[1].as_bytes())?)?;
-        let payload = general_purpose::URL_SAFE
-            .decode(parts[2].as_bytes())?
-            .to_vec();
+        let username = String::from_utf8(decode_part(parts[1], "username")?)
+            .map_err(|_| Error::TokenMalformed("username is not valid UTF-8".to_string()))?;
+        let payload = decode_part(parts[2], "payload")?;
         if parts.len() == 3 {
             return Ok(HashCookieTokenPayload {
                 exp,
@@ -51,9 +52,7 @@ impl HashCookieTokenPayload {
                 nonce: None,
             });
         }
-        let nonce = general_purpose::URL_SAFE
-            .decode(parts[3].as_bytes())?
-            .to_vec();
+        let nonce = decode_part(parts[3], "nonce")?;
         Ok(HashCookieTokenPayload {
             exp,
             username,
@@ -66,68 +65,91 @@ impl HashCookieTokenPayload {
         self.username.clone()
     }
 
-    pub fn try_get_json_payload(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        let payload = String::from_utf8(self.payload.clone())?;
-        let json_payload: serde_json::Value = serde_json::from_str(&payload)?;
+    pub fn try_get_json_payload(&self) -> Result<serde_json::Value> {
+        let payload = String::from_utf8(self.payload.clone())
+            .map_err(|_| Error::TokenMalformed("payload is not valid UTF-8".to_string()))?;
+        let json_payload: serde_json::Value = serde_json::from_str(&payload)
+            .map_err(|e| Error::TokenMalformed(format!("payload is not valid JSON: {}", e)))?;
         Ok(json_payload)
     }
 }
 
+fn decode_part(part: &str, what: &str) -> Result<Vec<u8>> {
+    general_purpose::URL_SAFE
+        .decode(part.as_bytes())
+        .map_err(|e| Error::TokenMalformed(format!("Error decoding {}: {}", what, e)))
+}
+
 const IV_SIZE: usize = 16;
 const KEY_SIZE: usize = 32;
 
 impl HashCookieToken {
-    pub fn new_from_token(
-        token: String,
-        user: User,
-    ) -> Result<HashCookieToken, Box<dyn std::error::Error>> {
+    pub fn new_from_token(token: String, user: User) -> Result<HashCookieToken> {
         let mut parts = HashCookieTokenPayload::new(token)?;
 
         if parts.username != user.username {
-            return Err("Encrypted username does not match user".into());
+            return Err(Error::TokenMalformed(
+                "Encrypted username does not match user".to_string(),
+            ));
         }
 
         if parts.exp < chrono::Utc::now().timestamp() {
-            return Err("Token has expired".into());
+            return Err(Error::TokenExpired);
         }
 
         let nonce = match parts.nonce {
             Some(nonce) => nonce,
             None => {
-                return Err("No nonce found".into());
+                return Err(Error::TokenMalformed("No nonce found".to_string()));
             }
         };
 
-        // load jwt_secret from user hex encoded
-        let jwt_secret = hex::decode(user.jwt_secret)?;
-        let salt = hex::decode(user.salt)?;
+        // load jwt_secret from user hex encoded; a bad value is stored data the token
+        // cannot be checked against, not a configuration problem
+        let jwt_secret = hex::decode(user.jwt_secret.expose())
+            .map_err(|e| Error::TokenMalformed(format!("Invalid jwt_secret for user: {}", e)))?;
+        let salt = hex::decode(user.salt.expose())
+            .map_err(|e| Error::TokenMalformed(format!("Invalid salt for user: {}", e)))?;
 
-        let mut key_bytes: [u8; (IV_SIZE + KEY_SIZE)] = [0u8; (IV_SIZE + KEY_SIZE)];
+        let mut key_bytes: [u8; IV_SIZE + KEY_SIZE] = [0u8; IV_SIZE + KEY_SIZE];
         pbkdf2_hmac::<Sha256>(&jwt_secret, &salt, 10000, &mut key_bytes);
 
         let keyslice: [u8; KEY_SIZE] = key_bytes[IV_SIZE..].try_into().unwrap();
-        let nonceslice: [u8; 8] = nonce.try_into().unwrap();
+        let nonceslice: [u8; 8] = nonce
+            .try_into()
+            .map_err(|_| Error::TokenMalformed("nonce must be 8 bytes".to_string()))?;
         let mut cipher = ChaCha20Legacy::new(&keyslice.into(), &nonceslice.into());
 
         cipher.apply_keystream(parts.payload.as_mut_slice());
-        let decoded_token_payload = String::from_utf8(parts.payload.to_vec())?;
+        let decoded_token_payload = String::from_utf8(parts.payload.to_vec())
+            .map_err(|_| Error::TokenMalformed("Error decrypting payload".to_string()))?;
         let decoded_payload = HashCookieTokenPayload::new(decoded_token_payload)?;
 
         let json_payload = decoded_payload.try_get_json_payload()?;
-        debug_println!("hashcookie decrypted json_payload: {:?}", json_payload);
+        // the payload holds dbauth, only log which keys it has
+        debug_println!(
+            "hashcookie decrypted json_payload keys: {:?}",
+            json_payload
+                .as_object()
+                .map(|payload| payload.keys().collect::<Vec<_>>())
+        );
 
         if decoded_payload.exp != parts.exp {
-            return Err("Encrypted exp does not match payload".into());
+            return Err(Error::TokenMalformed(
+                "Encrypted exp does not match payload".to_string(),
+            ));
         }
 
         if decoded_payload.username != parts.username {
-            return Err("Encrypted username does not match payload".into());
+            return Err(Error::TokenMalformed(
+                "Encrypted username does not match payload".to_string(),
+            ));
         }
 
         Ok(HashCookieToken {
             exp: decoded_payload.exp,
             username: decoded_payload.username,
-            dbauth: json_payload["dbauth"].as_str().map(|s| s.to_string()),
+            dbauth: json_payload["dbauth"].as_str().map(Secret::from),
         })
     }
 }
@@ -1,19 +1,69 @@
 pub mod admin;
 pub mod config;
+pub mod credits;
 mod debug;
+pub mod error;
 pub mod hashcookie;
 pub mod orm;
+pub mod pool;
+pub mod retry;
 pub mod sctx;
+pub mod secret;
+pub mod token;
+
+pub use error::{Error, Result};
+pub use secret::Secret;
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn docker_job_fixture() -> orm::DockerJob {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "metadata_id": 2,
+            "name": "job",
+            "description": null,
+            "deleted": false,
+            "workflow_state": "Running",
+            "gpu_capacity": 0.0,
+            "cpu_seconds": 0.0,
+            "current_cpu": 0.0,
+            "cpu_capacity": 1.0,
+            "ram_gb_seconds": 0.0,
+            "current_ram_gb": 0.0,
+            "ram_gb_capacity": 1.0,
+            "net_rx_gb": 0.0,
+            "current_net_rx_gb": 0.0,
+            "net_tx_gb": 0.0,
+            "current_net_tx_gb": 0.0,
+            "total_cost": 0.0,
+            "crg_id": null
+        }))
+        .unwrap()
+    }
+
+    fn crg_fixture_json() -> serde_json::Value {
+        serde_json::json!({
+            "id": 12,
+            "metadata_id": 34,
+            "name": "gpu pool",
+            "description": null,
+            "deleted": false,
+            "cost_per_cpu_hour": "0.25",
+            "cost_per_gpu_hour": "2.5",
+            "cost_per_gb_hour": "0.01",
+            "cost_per_net_rx_gb": "0",
+            "cost_per_net_tx_gb": "0.09",
+            "deployment_base_url": "https://example.com"
+        })
+    }
+
     const TEST_TOKEN: &str = "pxy.kdIWqsOqbo9UgzdT.rdNKj9DRqwZoKvwkDyJNfvADfNKqtyix1RM";
 
     #[tokio::test]
     async fn test_get_config() {
-        let config = config::MirandaConfig::new_from_default().unwrap();
+        config::MirandaConfig::new_from_default().unwrap();
     }
 
     #[tokio::test]
@@ -67,7 +117,7 @@ mod tests {
         let new_cpu_seconds = ob.cpu_seconds() + 1.2;
 
         ob.set_workflow_state(new_state.clone());
-        ob.set_cpu_seconds(new_cpu_seconds.clone());
+        ob.set_cpu_seconds(new_cpu_seconds);
 
         orm::update(&mut sc, &mut ob)
             .await
@@ -101,7 +151,7 @@ mod tests {
             .await
             .unwrap();
 
-        let mut ob = orm::find_by_id::<orm::KnowledgeObject>(&mut sc, 1)
+        let ob = orm::find_by_id::<orm::KnowledgeObject>(&mut sc, 1)
             .await
             .expect("Error finding KO");
 
@@ -120,7 +170,7 @@ mod tests {
         let username = hashcookie::HashCookieTokenPayload::new(token.clone())
             .expect("Error parsing token")
             .get_username();
-        let user = admin::users::find_user_by_username(&mut sctx.pool, &username)
+        let user = admin::users::find_user_by_username(&sctx.pool, &username)
             .await
             .expect("Error finding user");
         println!("{:?}", user);
@@ -130,4 +180,861 @@ mod tests {
             panic!("Error parsing token");
         }
     }
+
+    #[test]
+    fn test_error_kinds() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+
+        let err = config::PartialMirandaConfig::new_from_token_string(String::from("pxy.nopass"))
+            .unwrap_err();
+        assert!(matches!(err, Error::TokenMalformed(_)));
+
+        let user = admin::users::User {
+            id: 1,
+            username: String::from("webadmin"),
+            email: String::new(),
+            avatar: String::new(),
+            first_name: None,
+            last_name: None,
+            consented: true,
+            organization_id: 1,
+            jwt_secret: Secret::default(),
+            salt: Secret::default(),
+        };
+        let token =
+            String::from("1711663072.d2ViYWRtaW4=.NvnxNf4Aw5PBBKH7O9K5CBQqlaRo2QlGwF5U_JwVAli2EIaUQFJmTxGZAqx0IX406jzhYYjc4tjPYD1pMTyfdkChmpaoJkUABaWQVhn88bZVOvPHxXsPBJ-oCtjPvo6scYV9iOk434HNDUyZajWLh51GbQo29WoVYtTZ3TS8BzajIC0gB-T45qJJJ4iZQffZ099xPIYhXwWczWo4.4Kojp-2BAi0=");
+        let err = hashcookie::HashCookieToken::new_from_token(token, user.clone()).unwrap_err();
+        assert!(matches!(err, Error::TokenExpired));
+
+        // a user row whose secrets are not hex is bad data, not bad configuration
+        use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+        let token = format!(
+            "{}.{}.{}.{}",
+            chrono::Utc::now().timestamp() + 3600,
+            URL_SAFE.encode("webadmin"),
+            URL_SAFE.encode("payload"),
+            URL_SAFE.encode([0u8; 8])
+        );
+        let bad_secret = admin::users::User {
+            jwt_secret: Secret::from("not hex"),
+            ..user
+        };
+        let err = hashcookie::HashCookieToken::new_from_token(token, bad_secret).unwrap_err();
+        assert!(matches!(err, Error::TokenMalformed(why) if why.contains("jwt_secret")));
+
+        let err: Error = sqlx::Error::RowNotFound.into();
+        assert!(matches!(err, Error::NotFound(_)));
+        let err: Error = sqlx::Error::PoolTimedOut.into();
+        assert!(err.is_transient());
+        let err: Error = sqlx::Error::Protocol("unexpected packet".to_string()).into();
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn test_query_builder_sql() {
+        let q = orm::query::<orm::DockerJob>()
+            .filter_eq("workflow_state", orm::docker_job::WorkflowState::Running)
+            .filter_in("crg_id", vec![1, 2, 3])
+            .order_by("id")
+            .limit(50);
+        assert_eq!(
+            q.to_sql(),
+            "SELECT * FROM v_docker_job WHERE `workflow_state` = ? AND `crg_id` IN (?, ?, ?) ORDER BY `id` ASC LIMIT 50"
+        );
+
+        let q = orm::query::<orm::KnowledgeObject>().filter_eq("id; DROP TABLE x", 1);
+        assert_eq!(q.to_sql(), "SELECT * FROM v_knowledge_object");
+    }
+
+    #[test]
+    fn test_create_builder_changeset() {
+        use orm::ORMObjectBuilder;
+
+        let builder = orm::KnowledgeObject::builder(String::from("ko"));
+        let changeset = builder.get_changeset().unwrap();
+        assert_eq!(changeset.len(), 1);
+        assert_eq!(changeset["name"], "a28=");
+
+        let err = orm::ComputeResourceGroup::builder(String::from("crg"))
+            .cost_per_cpu_hour(orm::BigDecimal::from(1))
+            .get_changeset()
+            .unwrap_err();
+        match err {
+            Error::InvalidArgument(msg) => {
+                assert!(msg.contains("cost_per_gpu_hour"));
+                assert!(!msg.contains("cost_per_cpu_hour"));
+                assert!(!msg.contains("deployment_base_url"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_orm_object_serde_roundtrip() {
+        use orm::ORMObject;
+
+        let json = crg_fixture_json();
+        let crg: orm::ComputeResourceGroup = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(crg.id(), 12);
+        assert_eq!(crg.metadata_id(), 34);
+
+        let copy = crg.clone();
+        let value = serde_json::to_value(&copy).unwrap();
+        assert_eq!(value, json);
+        assert!(value.get("_changeset").is_none());
+    }
+
+    #[test]
+    fn test_changeset_coalesces() {
+        use orm::ORMObject;
+
+        let mut job = docker_job_fixture();
+        assert!(!job.is_dirty());
+
+        for i in 0..500 {
+            job.set_cpu_seconds(i as f32);
+        }
+        job.set_crg_id(Some(3));
+        assert!(job.is_dirty());
+        assert_eq!(job.dirty_fields(), vec!["cpu_seconds", "crg_id"]);
+        assert_eq!(
+            orm::changeset_json(job.changeset()),
+            r#"[{"cpu_seconds":"499","crg_id":"3"}]"#
+        );
+    }
+
+    #[test]
+    fn test_reapply_changeset_on_conflict() {
+        use orm::ORMObject;
+
+        let mut pending = docker_job_fixture();
+        pending.set_workflow_state(orm::docker_job::WorkflowState::Exited);
+
+        let mut fresh = docker_job_fixture();
+        fresh.set_cpu_seconds(42.0);
+        fresh.get_changeset().clear();
+
+        let merged = orm::reapply_changeset(&pending, fresh).unwrap();
+        assert_eq!(
+            merged.workflow_state(),
+            orm::docker_job::WorkflowState::Exited
+        );
+        assert_eq!(merged.cpu_seconds(), 42.0);
+        assert_eq!(merged.dirty_fields(), vec!["workflow_state"]);
+    }
+
+    #[tokio::test]
+    async fn test_update_if_unchanged_needs_version() {
+        use orm::ORMObject;
+
+        // neither call gets as far as connecting
+        let mut sctx = sctx::SecurityContext::new_lazy("alice", "secret");
+        let mut job = docker_job_fixture();
+        assert_eq!(job.last_updated(), None);
+        job.set_cpu_seconds(1.0);
+        assert!(matches!(
+            orm::update_if_unchanged(&mut sctx, &mut job).await,
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(job.is_dirty());
+
+        let version = Some(chrono::Utc::now());
+        job.get_changeset().clear();
+        job.set_last_updated(version);
+        orm::update(&mut sctx, &mut job).await.unwrap();
+        assert_eq!(job.last_updated(), version);
+    }
+
+    #[test]
+    fn test_workflow_state_transitions() {
+        use orm::docker_job::WorkflowState;
+
+        assert!(!WorkflowState::Exited.can_transition_to(&WorkflowState::Running));
+        assert!(WorkflowState::Starting.can_transition_to(&WorkflowState::Running));
+        assert!(WorkflowState::Running.can_transition_to(&WorkflowState::Exited));
+        assert!(!WorkflowState::Running.can_transition_to(&WorkflowState::Uninitialized));
+        for state in WorkflowState::Restarting.allowed_next() {
+            assert!(!state.allowed_next().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_meter_integrates_samples() {
+        use chrono::{Duration, TimeZone, Utc};
+        use orm::docker_job::{Meter, UsageSample};
+
+        let crg: orm::ComputeResourceGroup = serde_json::from_value(crg_fixture_json()).unwrap();
+        let mut job = docker_job_fixture();
+        let mut meter = Meter::new(Some(&crg));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        meter.sample(
+            &mut job,
+            UsageSample {
+                at: start,
+                cpu: 1.0,
+                ram_gb: 4.0,
+                net_rx_gb: 10.0,
+                net_tx_gb: 1.0,
+            },
+        );
+        assert_eq!(job.cpu_seconds(), 0.0);
+        meter.sample(
+            &mut job,
+            UsageSample {
+                at: start + Duration::hours(1),
+                cpu: 3.0,
+                ram_gb: 4.0,
+                net_rx_gb: 12.0,
+                net_tx_gb: 3.0,
+            },
+        );
+
+        assert_eq!(job.cpu_seconds(), 7200.0);
+        assert_eq!(job.ram_gb_seconds(), 14400.0);
+        assert_eq!(job.net_rx_gb(), 2.0);
+        assert_eq!(job.net_tx_gb(), 2.0);
+        assert_eq!(job.current_cpu(), 3.0);
+        // 2 cpu hours * 0.25 + 4 gb hours * 0.01 + 2 gb tx * 0.09
+        assert!((job.total_cost() - 0.72).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_crg_estimate() {
+        use orm::crg::UsageSpec;
+        use std::str::FromStr;
+
+        let crg: orm::ComputeResourceGroup = serde_json::from_value(crg_fixture_json()).unwrap();
+        let dec = |s: &str| orm::BigDecimal::from_str(s).unwrap();
+
+        let cost = crg.estimate(&UsageSpec {
+            cpu_hours: dec("0.1"),
+            gpu_hours: dec("0.2"),
+            ram_gb_hours: dec("3"),
+            net_rx_gb: dec("100"),
+            net_tx_gb: dec("1"),
+        });
+        assert_eq!(cost.cpu, dec("0.025"));
+        assert_eq!(cost.gpu, dec("0.5"));
+        assert_eq!(cost.ram, dec("0.03"));
+        assert_eq!(cost.net_rx, dec("0"));
+        assert_eq!(cost.net_tx, dec("0.09"));
+        assert_eq!(cost.total, dec("0.645"));
+
+        let mut job = docker_job_fixture();
+        job.set_cpu_capacity(0.1);
+        job.set_ram_gb_capacity(2.0);
+        let remaining = crg.project_remaining(&job, &dec("10"));
+        assert_eq!(remaining.cpu, dec("0.25"));
+        assert_eq!(remaining.total, dec("0.45"));
+    }
+
+    #[derive(Default)]
+    struct MemoryCreditBackend {
+        entries: Vec<(String, credits::LedgerEntry)>,
+    }
+
+    impl credits::CreditBackend for MemoryCreditBackend {
+        async fn find_by_key(&mut self, key: &str) -> Result<Option<credits::LedgerEntry>> {
+            Ok(self
+                .entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, e)| e.clone()))
+        }
+
+        async fn transact_with_key(
+            &mut self,
+            key: &str,
+            amount: &orm::BigDecimal,
+            statement: &str,
+        ) -> Result<Option<credits::LedgerEntry>> {
+            if self.entries.iter().any(|(k, _)| k == key) {
+                return Ok(None);
+            }
+            let entry = credits::LedgerEntry {
+                id: self.entries.len() as i32 + 1,
+                amount: amount.clone(),
+                statement: statement.to_string(),
+                created_at: chrono::Utc::now(),
+            };
+            self.entries.push((key.to_string(), entry.clone()));
+            Ok(Some(entry))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transact_idempotent_replay() {
+        let mut backend = MemoryCreditBackend::default();
+        let amount = orm::BigDecimal::from(-5);
+
+        let first =
+            credits::transact_idempotent(&mut backend, "job-1-settle", amount.clone(), "job 1")
+                .await
+                .unwrap();
+        let replay =
+            credits::transact_idempotent(&mut backend, "job-1-settle", amount.clone(), "job 1")
+                .await
+                .unwrap();
+        assert_eq!(first.id, replay.id);
+        assert_eq!(backend.entries.len(), 1);
+
+        let err = credits::transact_idempotent(
+            &mut backend,
+            "job-1-settle",
+            orm::BigDecimal::from(-6),
+            "job 1",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Conflict(_)));
+        assert_eq!(backend.entries.len(), 1);
+
+        credits::transact_idempotent(&mut backend, "job-2-settle", amount, "job 2")
+            .await
+            .unwrap();
+        assert_eq!(backend.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_ledger_range_next_page() {
+        let first = credits::LedgerRange {
+            since: Some(chrono::Utc::now()),
+            limit: 20,
+            ..credits::LedgerRange::default()
+        };
+        let second = first.next_page();
+        assert_eq!(second.offset, 20);
+        assert_eq!(second.limit, 20);
+        assert_eq!(second.since, first.since);
+        assert_eq!(second.next_page().offset, 40);
+    }
+
+    #[tokio::test]
+    async fn test_credit_hold_arguments() {
+        // invalid amounts are refused before connecting
+        let mut sctx = sctx::SecurityContext::new_lazy("alice", "secret");
+        for amount in [0, -5] {
+            assert!(matches!(
+                credits::hold(&mut sctx, orm::BigDecimal::from(amount), "job 1").await,
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        let hold = credits::CreditHold {
+            id: 1,
+            amount: orm::BigDecimal::from(10),
+        };
+        for amount in [0, -5, 11] {
+            assert!(matches!(
+                hold.capture(&mut sctx, orm::BigDecimal::from(amount), "job 1")
+                    .await,
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        // a failed capture leaves the hold to settle
+        assert_eq!(hold.amount, orm::BigDecimal::from(10));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        use std::time::Duration;
+
+        let policy = retry::RetryPolicy {
+            jitter: false,
+            ..retry::RetryPolicy::default()
+        };
+        let transient: Error = sqlx::Error::PoolTimedOut.into();
+        assert!(policy.should_retry(&transient, 1));
+        assert!(policy.should_retry(&transient, 2));
+        assert!(!policy.should_retry(&transient, 3));
+        assert!(!policy.should_retry(&Error::NotFound(String::new()), 1));
+        assert!(!policy.should_retry(&sqlx::Error::RowNotFound.into(), 1));
+        assert!(!retry::RetryPolicy::none().should_retry(&transient, 1));
+        assert!(!policy.non_idempotent().should_retry(&transient, 1));
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+        assert_eq!(policy.backoff(20), Duration::from_secs(2));
+
+        let jittered = retry::RetryPolicy::default().backoff(2);
+        assert!(jittered >= Duration::from_millis(50) && jittered <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_pool_settings_from_config() {
+        use std::time::Duration;
+
+        let config: config::MirandaConfig = serde_json::from_str(
+            r#"{"host": "localhost", "port": "3306", "user": "u", "password": "p", "database": "db"}"#,
+        )
+        .unwrap();
+        let settings = sctx::PoolSettings::new_from_config(&config);
+        assert_eq!(settings.max_connections, 1);
+        assert_eq!(settings.ping_after_idle, Duration::from_secs(60));
+
+        let tuned = config
+            .clone()
+            .merge_into_new(config::PartialMirandaConfig {
+                max_pool_connections: Some(8),
+                min_pool_connections: Some(2),
+                idle_timeout_secs: Some(0),
+                max_lifetime_secs: Some(600),
+                ping_after_idle_secs: Some(15),
+                statement_cache_capacity: Some(0),
+                ..config::PartialMirandaConfig::default()
+            })
+            .unwrap();
+        let settings = sctx::PoolSettings::new_from_config(&tuned);
+        assert_eq!(settings.max_connections, 8);
+        assert_eq!(settings.min_connections, 2);
+        assert_eq!(settings.idle_timeout, None);
+        assert_eq!(settings.max_lifetime, Some(Duration::from_secs(600)));
+        assert_eq!(settings.ping_after_idle, Duration::from_secs(15));
+        assert_eq!(settings.statement_cache_capacity, 0);
+        assert_eq!(settings.acquire_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_tls_settings_from_config() {
+        use sqlx::mysql::MySqlSslMode;
+
+        let config: config::MirandaConfig = serde_json::from_str(
+            r#"{"host": "localhost", "port": "3306", "user": "u", "password": "p", "database": "db"}"#,
+        )
+        .unwrap();
+        let tls = sctx::TlsSettings::new_from_config(&config).unwrap();
+        assert!(matches!(tls.ssl_mode, MySqlSslMode::Preferred));
+        assert!(tls.mysql_async_ssl_opts().is_none());
+
+        let verified = config::PartialMirandaConfig {
+            ssl_mode: Some("verify_identity".to_string()),
+            ssl_ca: Some("/etc/miranda/ca.pem".to_string()),
+            ..config::PartialMirandaConfig::default()
+        };
+        let tls =
+            sctx::TlsSettings::new_from_config(&config.clone().merge_into_new(verified).unwrap())
+                .unwrap();
+        assert!(matches!(tls.ssl_mode, MySqlSslMode::VerifyIdentity));
+        let opts = tls.mysql_async_ssl_opts().unwrap();
+        assert_eq!(opts.root_certs().len(), 1);
+        assert!(opts.disable_built_in_roots());
+        assert!(!opts.skip_domain_validation());
+
+        let bad_mode = config::PartialMirandaConfig {
+            ssl_mode: Some("always".to_string()),
+            ..config::PartialMirandaConfig::default()
+        };
+        let bad_mode = config.clone().merge_into_new(bad_mode).unwrap();
+        assert!(matches!(
+            sctx::TlsSettings::new_from_config(&bad_mode),
+            Err(Error::Config(_))
+        ));
+
+        let cert_only = config::PartialMirandaConfig {
+            ssl_cert: Some("client.pem".to_string()),
+            ..config::PartialMirandaConfig::default()
+        };
+        let cert_only = config.clone().merge_into_new(cert_only).unwrap();
+        assert!(matches!(
+            sctx::TlsSettings::new_from_config(&cert_only),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_secret_redacted() {
+        let config: config::MirandaConfig = serde_json::from_str(
+            r#"{"host": "localhost", "port": "3306", "user": "u", "password": "hunter2", "database": "db"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.password.expose(), "hunter2");
+        assert!(!format!("{:?}", config).contains("hunter2"));
+        assert_eq!(config.password.to_string(), "***");
+        // serializing keeps the value so configs can be written back
+        assert!(serde_json::to_string(&config).unwrap().contains("hunter2"));
+
+        let partial =
+            config::PartialMirandaConfig::new_from_token_string(String::from(TEST_TOKEN)).unwrap();
+        assert!(!format!("{:?}", partial).contains("rdNKj9DRqwZoKvwkDyJNfvADfNKqtyix1RM"));
+    }
+
+    #[test]
+    fn test_layered_config() {
+        use config::{ConfigLoader, ConfigSource, PartialMirandaConfig};
+        use std::collections::HashMap;
+        use std::path::PathBuf;
+
+        let system: PartialMirandaConfig = serde_json::from_str(
+            r#"{"host": "db.internal", "port": "3306", "user": "u", "password": "p", "database": "miranda"}"#,
+        )
+        .unwrap();
+        let user: PartialMirandaConfig =
+            serde_json::from_str(r#"{"host": "localhost", "max_pool_connections": 4}"#).unwrap();
+        let vars = HashMap::from([
+            ("MIRANDA_PORT", "3307"),
+            ("MIRANDA_TOKEN", TEST_TOKEN),
+            ("MIRANDA_USER", "ignored"),
+        ]);
+        let env =
+            PartialMirandaConfig::new_from_env_with(|name| vars.get(name).map(|v| v.to_string()))
+                .unwrap();
+        let programmatic = PartialMirandaConfig {
+            max_pool_connections: Some(16),
+            ..PartialMirandaConfig::default()
+        };
+
+        let system_path = PathBuf::from("/etc/miranda/config.json");
+        let (config, report) = ConfigLoader::merge_layers(vec![
+            (ConfigSource::SystemFile(system_path.clone()), system),
+            (ConfigSource::UserFile(PathBuf::from("config.json")), user),
+            (ConfigSource::Env, env),
+            (ConfigSource::Programmatic, programmatic),
+        ])
+        .unwrap();
+        assert_eq!(config.host, "localhost");
+        assert_eq!(config.port, "3307");
+        assert_eq!(config.user, "pxy.kdIWqsOqbo9UgzdT");
+        assert_eq!(config.database, "miranda");
+        assert_eq!(config.max_pool_connections, Some(16));
+        assert_eq!(
+            report.source_of("database"),
+            Some(&ConfigSource::SystemFile(system_path))
+        );
+        assert!(matches!(
+            report.source_of("host"),
+            Some(ConfigSource::UserFile(_))
+        ));
+        assert_eq!(report.source_of("password"), Some(&ConfigSource::Env));
+        assert_eq!(
+            report.source_of("max_pool_connections"),
+            Some(&ConfigSource::Programmatic)
+        );
+        assert_eq!(report.source_of("ssl_mode"), None);
+
+        let err = ConfigLoader::merge_layers(vec![(
+            ConfigSource::Programmatic,
+            PartialMirandaConfig::new_from_user("u".to_string(), "p".to_string()),
+        )])
+        .unwrap_err();
+        assert!(err.to_string().contains("host, port, database"));
+
+        let err = PartialMirandaConfig::new_from_env_with(|name| {
+            (name == "MIRANDA_MAX_POOL_CONNECTIONS").then(|| "many".to_string())
+        })
+        .unwrap_err();
+        assert!(matches!(err, Error::Config(_)));
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let valid: config::MirandaConfig = serde_json::from_str(
+            r#"{"host": "localhost", "port": "3306", "user": "pxy.abc123", "password": "secret", "database": "db"}"#,
+        )
+        .unwrap();
+        valid.validate().unwrap();
+
+        let mut punctuated = valid.clone();
+        punctuated.password = Secret::from("s3-cr_et!");
+        punctuated.validate().unwrap();
+
+        let invalid: config::MirandaConfig = serde_json::from_str(
+            r#"{"host": " ", "port": "70000", "user": "pxy.", "password": "a.b", "database": "",
+                "max_pool_connections": 2, "min_pool_connections": 5, "ssl_mode": "on",
+                "ssl_key": "client.key"}"#,
+        )
+        .unwrap();
+        let problems = match invalid.validate() {
+            Err(Error::InvalidConfig(problems)) => problems,
+            other => panic!("expected InvalidConfig, got {:?}", other),
+        };
+        let fields: Vec<&str> = problems
+            .iter()
+            .map(|p| p.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "host",
+                "port",
+                "database",
+                "user",
+                "password",
+                "min_pool_connections",
+                "ssl_mode",
+                "ssl_cert, ssl_key"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        use config::{ConfigFormat, ConfigProfiles};
+
+        let toml_profiles: ConfigProfiles = config::parse(
+            r#"
+            [default]
+            port = 3306
+            database = "miranda"
+            user = "app"
+            password = "secret"
+
+            [profiles.dev]
+            host = "localhost"
+
+            [profiles.prod]
+            host = "db.prod.internal"
+            port = "3307"
+            ssl_mode = "VERIFY_IDENTITY"
+            "#,
+            ConfigFormat::Toml,
+            "profiles.toml",
+        )
+        .unwrap();
+        assert_eq!(toml_profiles.names(), ["dev", "prod"]);
+        let dev = toml_profiles.select(Some("dev")).unwrap();
+        assert_eq!(dev.host, "localhost");
+        assert_eq!(dev.port, "3306");
+        let prod = toml_profiles.resolve("prod").unwrap();
+        assert_eq!(prod.port, "3307");
+        assert_eq!(prod.database, "miranda");
+        assert_eq!(prod.ssl_mode.as_deref(), Some("VERIFY_IDENTITY"));
+        assert!(matches!(
+            toml_profiles.resolve("staging"),
+            Err(Error::Config(_))
+        ));
+
+        let yaml_profiles: ConfigProfiles = config::parse(
+            "profiles:\n  staging:\n    host: staging.internal\n    port: 3306\n    user: app\n    password: secret\n",
+            ConfigFormat::Yaml,
+            "profiles.yaml",
+        )
+        .unwrap();
+        // the default section is optional, so a profile may lack required fields
+        assert!(matches!(
+            yaml_profiles.resolve("staging"),
+            Err(Error::Config(_))
+        ));
+        let staging = yaml_profiles.partial("staging").unwrap();
+        assert_eq!(staging.port.as_deref(), Some("3306"));
+        assert_eq!(staging.database, None);
+    }
+
+    #[test]
+    fn test_proxy_token() {
+        use token::ProxyToken;
+
+        let token: ProxyToken = TEST_TOKEN.parse().unwrap();
+        assert_eq!(token.username(), "pxy.kdIWqsOqbo9UgzdT");
+        assert_eq!(token.to_string(), TEST_TOKEN);
+        assert!(!format!("{:?}", token).contains("rdNKj9DRqwZoKvwkDyJNfvADfNKqtyix1RM"));
+        assert!(ProxyToken::is_proxy_user(&token.username()));
+        assert!(!ProxyToken::is_proxy_user("webadmin"));
+
+        let punctuated: ProxyToken = "pxy.name.pass-word_1!".parse().unwrap();
+        assert_eq!(punctuated.password().expose(), "pass-word_1!");
+        assert_eq!(punctuated.to_string(), "pxy.name.pass-word_1!");
+
+        for malformed in [
+            "kdIWqsOqbo9UgzdT.rdNKj9DRqwZoKvwkDyJNfvADfNKqtyix1RM",
+            "abc.kdIWqsOqbo9UgzdT.rdNKj9DRqwZoKvwkDyJNfvADfNKqtyix1RM",
+            "pxy.kdIWqsOqbo9UgzdT",
+            "pxy..password",
+            "pxy.name.",
+            "pxy.name.pass.word",
+            " pxy.name.password",
+        ] {
+            assert!(
+                matches!(
+                    malformed.parse::<ProxyToken>(),
+                    Err(Error::TokenMalformed(_))
+                ),
+                "{:?} should be rejected",
+                malformed
+            );
+        }
+
+        let path = std::env::temp_dir().join(format!("mirmod-token-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", TEST_TOKEN)).unwrap();
+        let from_file = ProxyToken::new_from_env_with(|name| {
+            (name == "MIRANDA_TOKEN_FILE").then(|| path.to_string_lossy().to_string())
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_file, Some(token));
+        assert_eq!(ProxyToken::new_from_env_with(|_| None).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_security_context_password() {
+        let password = "p@ss/w:rd#";
+        let sctx = sctx::SecurityContext::new_lazy("u", password);
+        assert!(!format!("{:?}", sctx).contains(password));
+
+        // passed as fields, so characters special in a URL are kept as they are
+        assert!(format!("{:?}", sctx.connect_options).contains(&format!("{:?}", password)));
+        assert_eq!(sctx.cdc_options().pass(), Some(password));
+        assert_eq!(sctx.cdc_options().user(), Some("u"));
+    }
+
+    #[tokio::test]
+    async fn test_claim_keepalive() {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let user = sctx::SecurityContext::new_lazy("alice", "secret");
+        assert!(matches!(
+            user.spawn_claim_keepalive(Duration::from_secs(1)),
+            Err(Error::InvalidArgument(_))
+        ));
+        let mut proxy = sctx::SecurityContext::new_lazy("pxy.abc", "secret");
+        assert!(matches!(
+            proxy.spawn_claim_keepalive(Duration::ZERO),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // the task's copy of the context holds the lease until the task exits
+        let lease = Arc::new(());
+        proxy.set_lease(lease.clone());
+        let mut keepalive = proxy
+            .spawn_claim_keepalive(Duration::from_millis(10))
+            .unwrap();
+        let failure = tokio::time::timeout(Duration::from_secs(5), keepalive.next_failure())
+            .await
+            .unwrap();
+        assert!(failure.is_some());
+        assert!(keepalive.is_running());
+        assert_eq!(Arc::strong_count(&lease), 3);
+
+        drop(keepalive);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::strong_count(&lease) > 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_context_cache() {
+        use sctx::{SecurityContext, UserContextCache, UserPoolSettings};
+
+        let password = Secret::from("secret");
+        let mut cache = UserContextCache::new(UserPoolSettings {
+            max_users: 2,
+            ..UserPoolSettings::default()
+        });
+        assert!(cache.get("alice", &password).is_none());
+        cache.insert(SecurityContext::new_lazy("alice", "secret"));
+        cache.insert(SecurityContext::new_lazy("bob", "secret"));
+        let alice = cache.get("alice", &password).unwrap();
+        assert_eq!(alice.auth_string[0].expose(), "alice");
+
+        // bob is now the least recently used and makes room for carol
+        cache.insert(SecurityContext::new_lazy("carol", "secret"));
+        assert!(cache.get("bob", &password).is_none());
+        assert!(cache.get("alice", &password).is_some());
+        assert!(cache.get("carol", &password).is_some());
+
+        // a wrong password misses without evicting the cached context
+        assert!(cache.get("alice", &Secret::from("changed")).is_none());
+        assert!(cache.get("alice", &password).is_some());
+
+        // a context for new credentials replaces the old one
+        cache.insert(SecurityContext::new_lazy("alice", "changed"));
+        assert!(cache.get("alice", &password).is_none());
+        assert!(cache.get("alice", &Secret::from("changed")).is_some());
+
+        let mut disabled = UserContextCache::new(UserPoolSettings {
+            max_users: 0,
+            ..UserPoolSettings::default()
+        });
+        disabled.insert(SecurityContext::new_lazy("alice", "secret"));
+        assert!(disabled.get("alice", &password).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pool_manager_cap() {
+        let manager = pool::PoolManager::new(
+            "localhost",
+            &3306,
+            "miranda",
+            pool::PoolManagerSettings {
+                max_total_connections: 1,
+                max_connections_per_user: 2,
+                ..pool::PoolManagerSettings::default()
+            },
+        );
+        let err = manager
+            .context("pxy.abc", &Secret::from("secret"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Exhausted(_)));
+        assert_eq!(
+            manager.metrics(),
+            pool::PoolMetrics {
+                misses: 1,
+                ..pool::PoolMetrics::default()
+            }
+        );
+        assert_eq!(manager.evict_idle().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pool_manager_wrong_password_keeps_pool() {
+        // nothing listens on port 1, so connecting with new credentials fails fast
+        let manager = pool::PoolManager::new(
+            "127.0.0.1",
+            &1,
+            "miranda",
+            pool::PoolManagerSettings {
+                pool: sctx::PoolSettings {
+                    acquire_timeout: std::time::Duration::from_millis(200),
+                    ..sctx::PoolSettings::default()
+                },
+                ..pool::PoolManagerSettings::default()
+            },
+        );
+        let leased = manager.adopt(sctx::SecurityContext::new_lazy("pxy.abc", "secret"));
+        assert!(manager
+            .context("pxy.abc", &Secret::from("wrong"))
+            .await
+            .is_err());
+        assert!(!leased.pool.is_closed());
+        assert_eq!(manager.metrics().pools, 1);
+
+        let reused = manager
+            .context("pxy.abc", &Secret::from("secret"))
+            .await
+            .unwrap();
+        assert!(!reused.pool.is_closed());
+        assert_eq!(manager.metrics().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_manager_cancelled_connect() {
+        use std::time::Duration;
+
+        // a non-routable address, so connecting hangs until cancelled or fails
+        let manager = pool::PoolManager::new(
+            "10.255.255.1",
+            &3306,
+            "miranda",
+            pool::PoolManagerSettings {
+                max_total_connections: 2,
+                max_connections_per_user: 2,
+                ..pool::PoolManagerSettings::default()
+            },
+        );
+        for _ in 0..3 {
+            let _ = tokio::time::timeout(
+                Duration::from_millis(50),
+                manager.context("pxy.abc", &Secret::from("secret")),
+            )
+            .await;
+            assert_eq!(manager.metrics().reserved_connections, 0);
+        }
+    }
 }
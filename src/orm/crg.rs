@@ -1,4 +1,5 @@
 use super::*;
+use std::str::FromStr;
 
 impl_orm_object!(
     ComputeResourceGroup,
@@ -10,3 +11,72 @@ impl_orm_object!(
     cost_per_net_tx_gb: BigDecimal,
     deployment_base_url: Option<String>
 );
+
+/// Resource usage to price, in the units of the group's rates.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UsageSpec {
+    pub cpu_hours: BigDecimal,
+    pub gpu_hours: BigDecimal,
+    pub ram_gb_hours: BigDecimal,
+    pub net_rx_gb: BigDecimal,
+    pub net_tx_gb: BigDecimal,
+}
+
+impl UsageSpec {
+    /// Usage of a job running at full capacity for `hours`. Network traffic is not
+    /// predictable from capacity and is left at zero.
+    pub fn for_capacity(job: &DockerJob, hours: &BigDecimal) -> UsageSpec {
+        UsageSpec {
+            cpu_hours: decimal_from_f32(job.cpu_capacity()) * hours,
+            gpu_hours: decimal_from_f32(job.gpu_capacity()) * hours,
+            ram_gb_hours: decimal_from_f32(job.ram_gb_capacity()) * hours,
+            net_rx_gb: BigDecimal::from(0),
+            net_tx_gb: BigDecimal::from(0),
+        }
+    }
+}
+
+/// Cost per usage dimension and in total, see [`ComputeResourceGroup::estimate`].
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CostBreakdown {
+    pub cpu: BigDecimal,
+    pub gpu: BigDecimal,
+    pub ram: BigDecimal,
+    pub net_rx: BigDecimal,
+    pub net_tx: BigDecimal,
+    pub total: BigDecimal,
+}
+
+impl ComputeResourceGroup {
+    pub fn estimate(&self, usage: &UsageSpec) -> CostBreakdown {
+        let cpu = &usage.cpu_hours * &self.cost_per_cpu_hour;
+        let gpu = &usage.gpu_hours * &self.cost_per_gpu_hour;
+        let ram = &usage.ram_gb_hours * &self.cost_per_gb_hour;
+        let net_rx = &usage.net_rx_gb * &self.cost_per_net_rx_gb;
+        let net_tx = &usage.net_tx_gb * &self.cost_per_net_tx_gb;
+        let total = &cpu + &gpu + &ram + &net_rx + &net_tx;
+        CostBreakdown {
+            cpu,
+            gpu,
+            ram,
+            net_rx,
+            net_tx,
+            total,
+        }
+    }
+
+    /// Cost of keeping `job` running at full capacity for another `hours`.
+    pub fn project_remaining(&self, job: &DockerJob, hours: &BigDecimal) -> CostBreakdown {
+        self.estimate(&UsageSpec::for_capacity(job, hours))
+    }
+}
+
+/// Converts through the shortest decimal representation so that e.g. 0.1f32
+/// becomes exactly 0.1 rather than 0.100000001490116...
+pub(crate) fn decimal_from_f32(value: f32) -> BigDecimal {
+    BigDecimal::from_str(&value.to_string()).unwrap_or_default()
+}
+
+pub(crate) fn decimal_from_f64(value: f64) -> BigDecimal {
+    BigDecimal::from_str(&value.to_string()).unwrap_or_default()
+}
@@ -1,5 +1,5 @@
 use super::*;
-use base64::{engine::general_purpose, Engine as _};
+use bigdecimal::ToPrimitive;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, sqlx::Type, serde::Deserialize, serde::Serialize)]
 #[sqlx(type_name = "workflow_state", rename_all = "UPPERCASE")]
@@ -24,6 +24,92 @@ impl WorkflowState {
     pub fn as_str(&self) -> String {
         format!("{:?}", self).to_uppercase()
     }
+
+    /// The states a job in this state may move to.
+    pub fn allowed_next(&self) -> &'static [WorkflowState] {
+        use WorkflowState::*;
+        match self {
+            Uninitialized => &[Starting, Error, Exited],
+            Starting => &[Ready, Running, Error, Exited],
+            Ready => &[ResumeReady, Running, Restarting, Error, Exited],
+            ResumeReady => &[Running, Error, Exited],
+            Running => &[Ready, Restarting, Error, Exited],
+            Error => &[Restarting, Exited],
+            Exited => &[Restarting],
+            Restarting => &[Starting, Ready, Error, Exited],
+        }
+    }
+
+    pub fn can_transition_to(&self, to: &WorkflowState) -> bool {
+        self.allowed_next().contains(to)
+    }
+}
+
+/// Tag used for the miranda_log entries written by [`DockerJob::transition`].
+pub const TRANSITION_LOG_TAG: i64 = 0;
+
+impl DockerJob {
+    /// Moves the job to `to` if the state machine allows it, writes the change and
+    /// logs it. Transitioning to the current state is a no-op.
+    ///
+    /// The stored state is checked under a row lock and the write and the log entry
+    /// are made in one transaction (a savepoint when `sctx` is already a
+    /// transaction). Fails with [`Error::Conflict`] if the stored state is no longer
+    /// the one this copy of the job was read with; reload it before retrying. On
+    /// failure nothing is written and the job keeps its previous state.
+    pub async fn transition(
+        &mut self,
+        sctx: &mut impl SecurityExecutor,
+        to: WorkflowState,
+    ) -> Result<()> {
+        let from = self.workflow_state();
+        if from == to {
+            return Ok(());
+        }
+        if !from.can_transition_to(&to) {
+            return Err(Error::InvalidTransition {
+                from: from.as_str(),
+                to: to.as_str(),
+            });
+        }
+
+        let mut conn = sctx.connection().await?;
+        let mut tx = conn.begin().await?;
+        let stored: WorkflowState =
+            sqlx::query_scalar("SELECT workflow_state FROM v_docker_job WHERE id = ? FOR UPDATE")
+                .bind(self.id())
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| Error::NotFound(format!("docker_job {}", self.id())))?;
+        if stored != from {
+            return Err(Error::Conflict(format!(
+                "docker_job {} is {}, not {}",
+                self.id(),
+                stored.as_str(),
+                from.as_str()
+            )));
+        }
+
+        let before = self.clone();
+        self.set_workflow_state(to.clone());
+        let result = async {
+            update(&mut tx, &mut *self).await?;
+            MirandaLog::create(
+                &mut tx,
+                format!("workflow_state {} -> {}", from.as_str(), to.as_str()),
+                TRANSITION_LOG_TAG,
+                MirandaClasses::DockerJob,
+                self.id() as i64,
+            )
+            .await?;
+            Ok(tx.commit().await?)
+        }
+        .await;
+        if result.is_err() {
+            *self = before;
+        }
+        result
+    }
 }
 
 impl_orm_object!(
@@ -44,3 +130,107 @@ impl_orm_object!(
     total_cost: f32,
     crg_id: Option<i32>
 );
+
+/// A point-in-time resource reading for a running job. `cpu` and `ram_gb` are
+/// instantaneous usage, the network values are the container's cumulative counters.
+#[derive(Clone, Debug)]
+pub struct UsageSample {
+    pub at: DateTime<Utc>,
+    pub cpu: f32,
+    pub ram_gb: f32,
+    pub net_rx_gb: f32,
+    pub net_tx_gb: f32,
+}
+
+/// Integrates periodic [`UsageSample`]s into a DockerJob's cumulative usage and
+/// cost fields. Samples only touch the job's changeset, [`Meter::flush`] writes
+/// everything in a single update.
+#[derive(Clone, Debug, Default)]
+pub struct Meter {
+    crg: Option<ComputeResourceGroup>,
+    last: Option<UsageSample>,
+}
+
+impl Meter {
+    /// Creates a meter charging the rates of `crg`, or nothing if the job has none.
+    pub fn new(crg: Option<&ComputeResourceGroup>) -> Meter {
+        Meter {
+            crg: crg.cloned(),
+            last: None,
+        }
+    }
+
+    /// Creates a meter for `job`, loading its compute resource group.
+    pub async fn for_job(sctx: &mut impl SecurityExecutor, job: &DockerJob) -> Result<Meter> {
+        match job.crg_id() {
+            Some(crg_id) => {
+                let crg = find_by_id::<ComputeResourceGroup>(sctx, crg_id).await?;
+                Ok(Meter::new(Some(&crg)))
+            }
+            None => Ok(Meter::new(None)),
+        }
+    }
+
+    /// Records a sample. The interval since the previous sample is integrated with
+    /// the trapezoidal rule and its cost added to `total_cost`. The first sample
+    /// only sets the current values.
+    pub fn sample(&mut self, job: &mut DockerJob, sample: UsageSample) {
+        if let Some(last) = &self.last {
+            let seconds = (sample.at - last.at).num_milliseconds() as f64 / 1000.0;
+            if seconds <= 0.0 {
+                debug_println!("[meter] Ignoring out of order sample at {}", sample.at);
+                return;
+            }
+            let cpu_seconds = (last.cpu + sample.cpu) as f64 / 2.0 * seconds;
+            let ram_gb_seconds = (last.ram_gb + sample.ram_gb) as f64 / 2.0 * seconds;
+            let gpu_hours = job.gpu_capacity() as f64 * seconds / 3600.0;
+            let net_rx_gb = counter_delta(last.net_rx_gb, sample.net_rx_gb);
+            let net_tx_gb = counter_delta(last.net_tx_gb, sample.net_tx_gb);
+
+            let cost = match &self.crg {
+                Some(crg) => crg
+                    .estimate(&crg::UsageSpec {
+                        cpu_hours: crg::decimal_from_f64(cpu_seconds / 3600.0),
+                        gpu_hours: crg::decimal_from_f64(gpu_hours),
+                        ram_gb_hours: crg::decimal_from_f64(ram_gb_seconds / 3600.0),
+                        net_rx_gb: crg::decimal_from_f64(net_rx_gb),
+                        net_tx_gb: crg::decimal_from_f64(net_tx_gb),
+                    })
+                    .total
+                    .to_f64()
+                    .unwrap_or(0.0),
+                None => 0.0,
+            };
+
+            job.set_cpu_seconds((job.cpu_seconds() as f64 + cpu_seconds) as f32);
+            job.set_ram_gb_seconds((job.ram_gb_seconds() as f64 + ram_gb_seconds) as f32);
+            job.set_net_rx_gb((job.net_rx_gb() as f64 + net_rx_gb) as f32);
+            job.set_net_tx_gb((job.net_tx_gb() as f64 + net_tx_gb) as f32);
+            job.set_total_cost((job.total_cost() as f64 + cost) as f32);
+        }
+
+        job.set_current_cpu(sample.cpu);
+        job.set_current_ram_gb(sample.ram_gb);
+        job.set_current_net_rx_gb(sample.net_rx_gb);
+        job.set_current_net_tx_gb(sample.net_tx_gb);
+        self.last = Some(sample);
+    }
+
+    /// Writes all accumulated changes to the job in one update.
+    pub async fn flush(
+        &mut self,
+        sctx: &mut impl SecurityExecutor,
+        job: &mut DockerJob,
+    ) -> Result<()> {
+        update(sctx, job).await
+    }
+}
+
+/// Growth of a cumulative counter, treating a decrease as a counter reset.
+fn counter_delta(last: f32, current: f32) -> f64 {
+    if current >= last {
+        (current - last) as f64
+    } else {
+        current as f64
+    }
+}
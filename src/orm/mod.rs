@@ -1,11 +1,19 @@
+use crate::error::{Error, Result};
+use crate::retry::retry;
+use crate::sctx::SecurityExecutor;
 use crate::{debug_println, sctx};
 use base64::{engine::general_purpose, Engine as _};
 pub use bigdecimal;
-use mysql_async::{prelude::Queryable, Conn, Opts};
+use mysql_async::{prelude::Queryable, Conn};
 use paste::paste;
 use serde_json_any_key::*;
+use sqlx::types::chrono::{DateTime, Utc};
 pub use sqlx::types::BigDecimal;
-use sqlx::{mysql::MySqlRow, Row};
+use sqlx::{
+    mysql::{MySql, MySqlRow},
+    Column, Connection, Row, TypeInfo,
+};
+use std::collections::BTreeMap;
 
 pub mod docker_job;
 pub use docker_job::DockerJob;
@@ -19,9 +27,19 @@ pub use crg::ComputeResourceGroup;
 pub mod storage_policy;
 pub use storage_policy::StoragePolicy;
 
+pub mod query;
+pub use query::{query, Query};
+
 const BIND_LIMIT: usize = 65535;
 
+/// Pending field updates keyed by column name, only the last value set for a
+/// field is kept.
+pub type Changeset = BTreeMap<String, String>;
+
 pub trait ORMUpdatableFieldValue {
+    /// Whether the column accepts NULL, i.e. may be left out when creating an object.
+    const NULLABLE: bool = false;
+
     fn get_changeset_value(&self) -> String;
 }
 
@@ -57,7 +75,7 @@ impl ORMUpdatableFieldValue for f64 {
 
 impl ORMUpdatableFieldValue for bool {
     fn get_changeset_value(&self) -> String {
-        if *self == true {
+        if *self {
             "1".to_string()
         } else {
             "0".to_string()
@@ -81,6 +99,8 @@ impl<T> ORMUpdatableFieldValue for Option<T>
 where
     T: ORMUpdatableFieldValue,
 {
+    const NULLABLE: bool = true;
+
     fn get_changeset_value(&self) -> String {
         match self {
             Some(value) => value.get_changeset_value(),
@@ -89,7 +109,9 @@ where
     }
 }
 
-pub trait ORMObject {
+pub trait ORMObject: Sized {
+    type Builder: ORMObjectBuilder;
+
     fn id(&self) -> i32;
     fn metadata_id(&self) -> i32;
     fn name(&self) -> String;
@@ -98,9 +120,62 @@ pub trait ORMObject {
     fn set_description(&mut self, description: String);
     fn deleted(&self) -> bool;
     fn set_deleted(&mut self, deleted: bool);
-    fn get_changeset(&mut self) -> &mut Vec<(String, String)>;
+    fn get_changeset(&mut self) -> &mut Changeset;
+    fn changeset(&self) -> &Changeset;
     fn table_name() -> String;
-    fn new_from_row(row: MySqlRow) -> Self;
+    fn try_from_row(row: MySqlRow) -> Result<Self>;
+    /// The `last_updated` timestamp read with the object, used as the version
+    /// token by [`update_if_unchanged`]. None if the view has no such column, or
+    /// after a plain [`update`], which does not read the new version back.
+    fn last_updated(&self) -> Option<DateTime<Utc>>;
+    fn set_last_updated(&mut self, last_updated: Option<DateTime<Utc>>);
+
+    /// Returns true if any field was set since the object was loaded or last updated.
+    fn is_dirty(&self) -> bool {
+        !self.changeset().is_empty()
+    }
+
+    fn dirty_fields(&self) -> Vec<String> {
+        self.changeset().keys().cloned().collect()
+    }
+}
+
+fn decode_error(row: &MySqlRow, column: &str, source: sqlx::Error) -> Error {
+    let sql_type = match row.try_column(column) {
+        Ok(col) => col.type_info().name().to_string(),
+        Err(_) => "missing".to_string(),
+    };
+    Error::Decode {
+        column: column.to_string(),
+        sql_type,
+        source,
+    }
+}
+
+/// Decodes a column, checking that its SQL type matches `T`.
+pub(crate) fn decode_column<'r, T>(row: &'r MySqlRow, column: &str) -> Result<T>
+where
+    T: sqlx::Decode<'r, MySql> + sqlx::Type<MySql>,
+{
+    row.try_get(column)
+        .map_err(|e| decode_error(row, column, e))
+}
+
+/// Decodes a column without checking its SQL type, for fields whose SQL type
+/// does not match the Rust type exactly (ENUMs, FLOAT vs DOUBLE).
+pub(crate) fn decode_column_unchecked<'r, T>(row: &'r MySqlRow, column: &str) -> Result<T>
+where
+    T: sqlx::Decode<'r, MySql>,
+{
+    row.try_get_unchecked(column)
+        .map_err(|e| decode_error(row, column, e))
+}
+
+/// Collects the field values for a new object, see [`create`].
+pub trait ORMObjectBuilder {
+    /// Returns the changeset for the create procedure, or an error listing every
+    /// required field that was not set.
+    fn get_changeset(&self) -> Result<Changeset>;
 }
 
 macro_rules! orm_object_getter {
@@ -118,7 +193,7 @@ macro_rules! orm_object_setter {
             pub fn [< set_ $field >] (&mut self, $field: $type) {
                 self.$field = $field.clone();
                 let value = $field.get_changeset_value();
-                self._changeset.push((stringify!($field).to_string(), value));
+                self._changeset.insert(stringify!($field).to_string(), value);
             }
         }
     };
@@ -127,9 +202,12 @@ pub(crate) use orm_object_setter;
 
 macro_rules! impl_orm_object {
     ($name:ident, $table_name:expr, $($field:ident: $type:ty),*) => {
-        #[derive(Debug)]
+        #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
         pub struct $name {
-            _changeset: Vec<(String, String)>,
+            #[serde(skip)]
+            _changeset: Changeset,
+            #[serde(skip)]
+            _last_updated: Option<DateTime<Utc>>,
             id: i32,
             metadata_id: i32,
             name: String,
@@ -138,11 +216,86 @@ macro_rules! impl_orm_object {
             $($field: $type),*
         }
 
+        paste! {
+            #[derive(Clone, Debug)]
+            pub struct [< $name Builder >] {
+                name: String,
+                description: Option<String>,
+                $($field: Option<$type>),*
+            }
+
+            impl [< $name Builder >] {
+                pub fn description(mut self, description: String) -> Self {
+                    self.description = Some(description);
+                    self
+                }
+
+                $(
+                    pub fn $field(mut self, $field: $type) -> Self {
+                        self.$field = Some($field);
+                        self
+                    }
+                )*
+            }
+
+            impl ORMObjectBuilder for [< $name Builder >] {
+                fn get_changeset(&self) -> Result<Changeset> {
+                    let mut changeset = Changeset::new();
+                    changeset.insert("name".to_string(), self.name.get_changeset_value());
+                    if let Some(description) = &self.description {
+                        changeset.insert("description".to_string(), description.get_changeset_value());
+                    }
+                    let mut missing: Vec<&str> = Vec::new();
+                    $(
+                        match &self.$field {
+                            Some(value) => {
+                                changeset.insert(stringify!($field).to_string(), value.get_changeset_value());
+                            }
+                            None if <$type as ORMUpdatableFieldValue>::NULLABLE => {}
+                            None => missing.push(stringify!($field)),
+                        }
+                    )*
+                    if !missing.is_empty() {
+                        return Err(Error::InvalidArgument(format!(
+                            "Missing required fields for {}: {}",
+                            stringify!($name),
+                            missing.join(", ")
+                        )));
+                    }
+                    Ok(changeset)
+                }
+            }
+
+            impl $name {
+                pub fn builder(name: String) -> [< $name Builder >] {
+                    [< $name Builder >] {
+                        name,
+                        description: None,
+                        $($field: None),*
+                    }
+                }
+            }
+        }
+
         impl ORMObject for $name {
-            fn get_changeset(&mut self) -> &mut Vec<(String, String)> {
+            type Builder = paste! { [< $name Builder >] };
+
+            fn get_changeset(&mut self) -> &mut Changeset {
                 &mut self._changeset
             }
 
+            fn changeset(&self) -> &Changeset {
+                &self._changeset
+            }
+
+            fn last_updated(&self) -> Option<DateTime<Utc>> {
+                self._last_updated
+            }
+
+            fn set_last_updated(&mut self, last_updated: Option<DateTime<Utc>>) {
+                self._last_updated = last_updated;
+            }
+
             fn table_name() -> String {
                 $table_name.to_string()
             }
@@ -159,32 +312,33 @@ macro_rules! impl_orm_object {
             }
             fn set_name(&mut self, name: String) {
                 self.name = name.clone();
-                self._changeset.push(("name".to_string(), general_purpose::STANDARD.encode(name)));
+                self._changeset.insert("name".to_string(), general_purpose::STANDARD.encode(name));
             }
             fn description(&self) -> Option<String> {
                 self.description.clone()
             }
             fn set_description(&mut self, description: String) {
                 self.description = Some(description.clone());
-                self._changeset.push(("description".to_string(), general_purpose::STANDARD.encode(description)));
+                self._changeset.insert("description".to_string(), general_purpose::STANDARD.encode(description));
             }
             fn deleted(&self) -> bool {
                 self.deleted
             }
             fn set_deleted(&mut self, deleted: bool) {
                 self.deleted = deleted;
-                self._changeset.push(("deleted".to_string(), deleted.to_string()));
+                self._changeset.insert("deleted".to_string(), deleted.to_string());
             }
-            fn new_from_row(row: MySqlRow) -> Self {
-                $name {
-                    _changeset: Vec::new(),
-                    id: row.get("id"),
-                    metadata_id: row.get("metadata_id"),
-                    name: row.get("name"),
-                    description: row.get("description"),
-                    deleted: row.get("deleted"),
-                    $($field: row.try_get_unchecked(stringify!($field)).expect("uh oh")),*
-                }
+            fn try_from_row(row: MySqlRow) -> Result<Self> {
+                Ok($name {
+                    _changeset: Changeset::new(),
+                    _last_updated: row.try_get("last_updated").ok(),
+                    id: decode_column(&row, "id")?,
+                    metadata_id: decode_column(&row, "metadata_id")?,
+                    name: decode_column(&row, "name")?,
+                    description: decode_column(&row, "description")?,
+                    deleted: decode_column(&row, "deleted")?,
+                    $($field: decode_column_unchecked(&row, stringify!($field))?),*
+                })
             }
         }
 
@@ -198,93 +352,289 @@ macro_rules! impl_orm_object {
 }
 pub(crate) use impl_orm_object;
 
-pub async fn find_by_id<T: ORMObject>(
-    sctx: &mut sctx::SecurityContext,
-    id: i32,
-) -> Result<T, Box<dyn std::error::Error>> {
+/// Serializes a changeset in the format expected by the sp_create_ and sp_update_ procedures.
+pub(crate) fn changeset_json(changeset: &Changeset) -> String {
+    format!("[{}]", changeset.to_json_map().unwrap())
+}
+
+pub async fn find_by_id<T: ORMObject>(sctx: &mut impl SecurityExecutor, id: i32) -> Result<T> {
     let table_name = T::table_name();
     debug_println!("Table name: {}", table_name);
 
     let query = format!("SELECT * FROM v_{} WHERE id = ?", table_name);
     debug_println!("Query: {}", query);
 
-    let result = sqlx::query(&query)
-        .bind(id)
-        .fetch_optional(&sctx.pool)
-        .await;
+    let result = retry!(
+        sctx.retry_policy(),
+        async {
+            let mut conn = sctx.connection().await?;
+            Ok::<_, Error>(
+                sqlx::query(&query)
+                    .bind(id)
+                    .fetch_optional(&mut *conn)
+                    .await?,
+            )
+        }
+        .await
+    );
 
     debug_println!("Result: {:?}", result);
 
     match result {
         Ok(row) => match row {
-            Some(row) => Ok(T::new_from_row(row)),
+            Some(row) => T::try_from_row(row),
             None => {
                 debug_println!("No row found");
-                Err("No row found".into())
+                Err(Error::NotFound("No row found".to_string()))
             }
         },
         Err(e) => {
             debug_println!("Error: {}", e);
-            Err(e.into())
+            Err(e)
         }
     }
 }
 
-pub async fn update<T: ORMObject>(
-    sc: &mut sctx::SecurityContext,
-    ob: &mut T,
-) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn create<T: ORMObject>(
+    sctx: &mut impl SecurityExecutor,
+    builder: T::Builder,
+) -> Result<T> {
+    let table_name = T::table_name();
+    let query = format!("CALL sp_create_{} (?)", table_name);
+    let changeset = builder.get_changeset()?;
+    let changeset_json = changeset_json(&changeset);
+    debug_println!("changeset {} {}", query, changeset_json);
+    let mut conn = sctx.connection().await?;
+    let row = sqlx::query(&query)
+        .bind(changeset_json)
+        .fetch_optional(&mut *conn)
+        .await?;
+    drop(conn);
+
+    // sqlx does not support getting by column name from rows returned by stored procedures
+    let id = match row {
+        Some(row) => match row.try_get::<i64, _>(0) {
+            Ok(id) => id,
+            Err(_) => row.try_get::<u64, _>(0)? as i64,
+        },
+        None => {
+            return Err(Error::NotFound(format!(
+                "sp_create_{} did not return an id",
+                table_name
+            )))
+        }
+    };
+    let id = i32::try_from(id)
+        .map_err(|_| Error::InvalidArgument(format!("Object id {} out of range", id)))?;
+    find_by_id::<T>(sctx, id).await
+}
+
+/// Writes the object's changeset. The new `last_updated` is not read back, so the
+/// object's version is cleared; reload it before using [`update_if_unchanged`].
+pub async fn update<T: ORMObject>(sc: &mut impl SecurityExecutor, ob: &mut T) -> Result<()> {
+    if !ob.is_dirty() {
+        debug_println!("update skipped, nothing changed");
+        return Ok(());
+    }
     let table_name = T::table_name();
     let query = format!("CALL sp_update_{} (?, ?)", table_name);
     let obid = ob.id();
     let changeset = ob.get_changeset();
-    let changeset_json = format!("[{}]", changeset.to_json_map().unwrap());
+    let changeset_json = changeset_json(changeset);
     debug_println!("changeset {} {}", query, changeset_json);
-    let result = sqlx::query(&query)
-        .bind(obid)
-        .bind(changeset_json)
-        .execute(&sc.pool)
-        .await;
+    let result = retry!(
+        sc.retry_policy(),
+        async {
+            let mut conn = sc.connection().await?;
+            Ok::<_, Error>(
+                sqlx::query(&query)
+                    .bind(obid)
+                    .bind(&changeset_json)
+                    .execute(&mut *conn)
+                    .await?,
+            )
+        }
+        .await
+    );
 
     match result {
         Ok(_) => {
             changeset.clear();
+            ob.set_last_updated(None);
             Ok(())
         }
-        Err(e) => Err(e.into()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Like [`update`], but fails with [`Error::Conflict`] if the row's `last_updated`
+/// no longer matches the value read with the object. The check and the update run
+/// in one transaction holding a row lock (a savepoint when `sc` is already a
+/// transaction).
+pub async fn update_if_unchanged<T: ORMObject>(
+    sc: &mut impl SecurityExecutor,
+    ob: &mut T,
+) -> Result<()> {
+    if !ob.is_dirty() {
+        debug_println!("update skipped, nothing changed");
+        return Ok(());
+    }
+    let table_name = T::table_name();
+    let expected = match ob.last_updated() {
+        Some(expected) => expected,
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "{} {} has no last_updated version to check against, reload it first",
+                table_name,
+                ob.id()
+            )))
+        }
+    };
+    let version_query = format!("SELECT last_updated FROM v_{} WHERE id = ?", table_name);
+
+    let mut conn = sc.connection().await?;
+    let mut tx = conn.begin().await?;
+    let current: Option<DateTime<Utc>> =
+        sqlx::query_scalar(&format!("{} FOR UPDATE", version_query))
+            .bind(ob.id())
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("{} {}", table_name, ob.id())))?;
+    if current != Some(expected) {
+        debug_println!("version conflict {:?} != {:?}", current, expected);
+        return Err(Error::Conflict(format!(
+            "{} {} was modified at {:?}",
+            table_name,
+            ob.id(),
+            current
+        )));
     }
+
+    let query = format!("CALL sp_update_{} (?, ?)", table_name);
+    let changeset_json = changeset_json(ob.changeset());
+    debug_println!("changeset {} {}", query, changeset_json);
+    sqlx::query(&query)
+        .bind(ob.id())
+        .bind(changeset_json)
+        .execute(&mut *tx)
+        .await?;
+    let updated: Option<DateTime<Utc>> = sqlx::query_scalar(&version_query)
+        .bind(ob.id())
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    ob.get_changeset().clear();
+    ob.set_last_updated(updated);
+    Ok(())
+}
+
+/// Runs [`update_if_unchanged`], and on conflict re-fetches the object, re-applies
+/// the pending changeset on top of the fresh values and tries again, at most
+/// `max_attempts` times in total. The re-fetch is a locking read, so inside a
+/// transaction it sees the latest committed row rather than the snapshot.
+pub async fn update_with_retry<T>(
+    sc: &mut impl SecurityExecutor,
+    ob: &mut T,
+    max_attempts: u32,
+) -> Result<()>
+where
+    T: ORMObject + serde::Serialize + serde::de::DeserializeOwned,
+{
+    let mut attempt = 1;
+    loop {
+        match update_if_unchanged(sc, ob).await {
+            Err(Error::Conflict(why)) if attempt < max_attempts => {
+                debug_println!("update attempt {} conflicted: {}", attempt, why);
+                let fresh = find_by_id_for_update::<T>(sc, ob.id()).await?;
+                *ob = reapply_changeset(ob, fresh)?;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Like [`find_by_id`], with a locking read.
+async fn find_by_id_for_update<T: ORMObject>(sc: &mut impl SecurityExecutor, id: i32) -> Result<T> {
+    let table_name = T::table_name();
+    let query = format!("SELECT * FROM v_{} WHERE id = ? FOR UPDATE", table_name);
+    let mut conn = sc.connection().await?;
+    let row = sqlx::query(&query)
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("{} {}", table_name, id)))?;
+    T::try_from_row(row)
+}
+
+/// Copies the dirty fields of `pending` onto `fresh` and carries over its changeset.
+pub(crate) fn reapply_changeset<T>(pending: &T, fresh: T) -> Result<T>
+where
+    T: ORMObject + serde::Serialize + serde::de::DeserializeOwned,
+{
+    let to_value = |ob: &T| {
+        serde_json::to_value(ob)
+            .map_err(|e| Error::InvalidArgument(format!("Error serializing object: {}", e)))
+    };
+    let pending_value = to_value(pending)?;
+    let mut merged = to_value(&fresh)?;
+    for field in pending.dirty_fields() {
+        merged[&field] = pending_value[&field].clone();
+    }
+    let mut merged: T = serde_json::from_value(merged)
+        .map_err(|e| Error::InvalidArgument(format!("Error deserializing object: {}", e)))?;
+    *merged.get_changeset() = pending.changeset().clone();
+    merged.set_last_updated(fresh.last_updated());
+    Ok(merged)
 }
 
 pub async fn delete<T: ORMObject>(
-    sc: &mut sctx::SecurityContext,
+    sc: &mut impl SecurityExecutor,
     ob: &mut T,
     delete_children: bool,
     hard_delete: bool,
-) -> Result<u64, Box<dyn std::error::Error>> {
-    let table_name = T::table_name();
-
+) -> Result<u64> {
     // Cascading delete by metadata_id
     if delete_children {
         let proc = if hard_delete {
-            format!("sp_delete_graph_by_mid")
+            "sp_delete_graph_by_mid"
         } else {
-            format!("sp_soft_delete_graph_by_mid")
+            "sp_soft_delete_graph_by_mid"
         };
         let query = format!("CALL {}(?)", proc);
-        let result = sqlx::query(&query)
-            .bind(ob.metadata_id())
-            .execute(&sc.pool)
-            .await?;
+        let result = retry!(
+            sc.retry_policy(),
+            async {
+                let mut conn = sc.connection().await?;
+                Ok::<_, Error>(
+                    sqlx::query(&query)
+                        .bind(ob.metadata_id())
+                        .execute(&mut *conn)
+                        .await?,
+                )
+            }
+            .await
+        )?;
         return Ok(result.rows_affected());
     }
 
     // Hard/soft delete by metadata_id
     if hard_delete {
         let query = "CALL sp_delete_object(?)";
-        let result = sqlx::query(query)
-            .bind(ob.metadata_id())
-            .execute(&sc.pool)
-            .await?;
+        let result = retry!(
+            sc.retry_policy(),
+            async {
+                let mut conn = sc.connection().await?;
+                Ok::<_, Error>(
+                    sqlx::query(query)
+                        .bind(ob.metadata_id())
+                        .execute(&mut *conn)
+                        .await?,
+                )
+            }
+            .await
+        )?;
         Ok(result.rows_affected())
     } else {
         // Soft delete: set deleted flag and update
@@ -306,19 +656,16 @@ pub fn get_class_id(id: i32) -> MirandaClasses {
 }
 
 pub struct MirandaLog {
-    id: i32,
-    created_at: sqlx::types::chrono::DateTime<sqlx::types::chrono::Utc>,
-    message: String,
-    tag: i32,
-    class_id: MirandaClasses,
-    instance_id: i32,
+    pub id: i32,
+    pub created_at: sqlx::types::chrono::DateTime<sqlx::types::chrono::Utc>,
+    pub message: String,
+    pub tag: i32,
+    pub class_id: MirandaClasses,
+    pub instance_id: i32,
 }
 
 impl MirandaLog {
-    pub async fn new_from_id(
-        sctx: &mut sctx::SecurityContext,
-        id: i32,
-    ) -> Result<MirandaLog, Box<dyn std::error::Error>> {
+    pub async fn new_from_id(sctx: &mut sctx::SecurityContext, id: i32) -> Result<MirandaLog> {
         let query = "SELECT * FROM v_miranda_log WHERE id = ?";
         let result = sqlx::query(query).bind(id).fetch_optional(&sctx.pool).await;
 
@@ -332,26 +679,27 @@ impl MirandaLog {
                     class_id: get_class_id(row.get("class_id")),
                     instance_id: row.get("instance_id"),
                 }),
-                None => Err("No row found".into()),
+                None => Err(Error::NotFound("No row found".to_string())),
             },
             Err(e) => Err(e.into()),
         }
     }
 
     pub async fn create(
-        sctx: &mut sctx::SecurityContext,
+        sctx: &mut impl SecurityExecutor,
         message: String,
         tag: i64,
         class_id: MirandaClasses,
         instance_id: i64,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<()> {
         let query = "CALL sp_log (?, ?, ?, ?)";
+        let mut conn = sctx.connection().await?;
         let result = sqlx::query(query)
             .bind(class_id as i64)
             .bind(instance_id)
             .bind(tag)
             .bind(message)
-            .execute(&sctx.pool)
+            .execute(&mut *conn)
             .await;
 
         match result {
@@ -391,9 +739,11 @@ impl RealtimeMessageTicket {
     pub async fn new_from_ticket(
         sctx: &mut sctx::SecurityContext,
         ticket: String,
-    ) -> Result<RealtimeMessageTicket, Box<dyn std::error::Error>> {
-        if sctx.is_admin != true {
-            return Err("Admin context required".into());
+    ) -> Result<RealtimeMessageTicket> {
+        if !sctx.is_admin {
+            return Err(Error::PermissionDenied(
+                "Admin context required".to_string(),
+            ));
         }
 
         let query = "SELECT * FROM realtime_message_ticket WHERE ticket = ?";
@@ -410,7 +760,7 @@ impl RealtimeMessageTicket {
                     creator_user_id: row.get("creator_user_id"),
                     created_at: row.get("created_at"),
                 }),
-                None => Err("No row found".into()),
+                None => Err(Error::NotFound("No row found".to_string())),
             },
             Err(e) => Err(e.into()),
         }
@@ -418,12 +768,12 @@ impl RealtimeMessageTicket {
 }
 
 impl RealtimeMessage {
-    pub async fn new_from_id(
-        sctx: &mut sctx::SecurityContext,
-        id: i32,
-    ) -> Result<RealtimeMessage, Box<dyn std::error::Error>> {
+    pub async fn new_from_id(sctx: &mut sctx::SecurityContext, id: i32) -> Result<RealtimeMessage> {
         let query = "SELECT * FROM v_realtime_message WHERE id = ?";
-        let result = sqlx::query(query).bind(id).fetch_optional(&sctx.pool).await;
+        let result = retry!(
+            sctx.retry_policy.clone(),
+            sqlx::query(query).bind(id).fetch_optional(&sctx.pool).await
+        );
 
         match result {
             Ok(row) => match row {
@@ -436,35 +786,38 @@ impl RealtimeMessage {
                     ticket: row.get("ticket"),
                     created_at: row.get("created_at"),
                 }),
-                None => Err("No row found".into()),
+                None => Err(Error::NotFound("No row found".to_string())),
             },
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
     pub async fn send_to_processor(
         sctx: &mut sctx::SecurityContext,
         payload: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<()> {
         let query = "CALL sp_send_message_to_processor (?)";
-        let result = sqlx::query(query).bind(payload).execute(&sctx.pool).await;
+        let result = retry!(
+            sctx.retry_policy.non_idempotent(),
+            sqlx::query(query).bind(&payload).execute(&sctx.pool).await
+        );
 
         match result {
             Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
-    pub async fn send_to_self(
-        sctx: &mut sctx::SecurityContext,
-        payload: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn send_to_self(sctx: &mut sctx::SecurityContext, payload: String) -> Result<()> {
         let query = "CALL sp_user_send_realtime_message (?)";
-        let result = sqlx::query(query).bind(payload).execute(&sctx.pool).await;
+        let result = retry!(
+            sctx.retry_policy.non_idempotent(),
+            sqlx::query(query).bind(&payload).execute(&sctx.pool).await
+        );
 
         match result {
             Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
@@ -473,31 +826,39 @@ impl RealtimeMessage {
         ko_id: i32,
         ticket: String,
         payload: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<()> {
         let query = "CALL sp_ko_send_realtime_message (?, ?, ?)";
-        let result = sqlx::query(query)
-            .bind(ticket)
-            .bind(ko_id)
-            .bind(payload)
-            .execute(&sctx.pool)
-            .await;
+        let result = retry!(
+            sctx.retry_policy.non_idempotent(),
+            sqlx::query(query)
+                .bind(&ticket)
+                .bind(ko_id)
+                .bind(&payload)
+                .execute(&sctx.pool)
+                .await
+        );
 
         match result {
             Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
     pub async fn consume_queue(
         sctx: &mut sctx::SecurityContext,
         count: i32,
-    ) -> Result<Vec<RealtimeMessage>, Box<dyn std::error::Error>> {
-        if sctx.is_admin != true {
-            return Err("Admin context required".into());
+    ) -> Result<Vec<RealtimeMessage>> {
+        if !sctx.is_admin {
+            return Err(Error::PermissionDenied(
+                "Admin context required".to_string(),
+            ));
         }
 
         let query = "CALL sp_consume_realtime_message_queue (?)";
-        let result = sqlx::query(query).bind(count).fetch_all(&sctx.pool).await;
+        let result = retry!(
+            sctx.retry_policy.non_idempotent(),
+            sqlx::query(query).bind(count).fetch_all(&sctx.pool).await
+        );
         let mut messages = Vec::new();
         for row in result? {
             // sqlx does not support getting by column name from rows returned by stored procedures
@@ -520,15 +881,16 @@ impl RealtimeMessage {
 }
 
 pub async fn transact_credits(
-    sctx: &mut sctx::SecurityContext,
+    sctx: &mut impl SecurityExecutor,
     amount: BigDecimal,
     statement: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<()> {
     let query = "CALL sp_transact_credits (NULL, ?, ?)";
+    let mut conn = sctx.connection().await?;
     let result = sqlx::query(query)
         .bind(amount)
         .bind(statement)
-        .execute(&sctx.pool)
+        .execute(&mut *conn)
         .await;
 
     match result {
@@ -546,8 +908,7 @@ pub async fn wait_for_cdc_event(
     // if query is killed (2013: Lost connection to MySQL server during query), return true
     // otherwise, false
 
-    let conf = Opts::from_url(&sctx.constr).unwrap();
-    let mut conn = match Conn::new(conf).await {
+    let mut conn = match Conn::new(sctx.cdc_options()).await {
         Ok(conn) => conn,
         Err(e) => {
             debug_println!("📜 wait_for_cdc_event error: {}", e);
@@ -589,14 +950,17 @@ impl WOBMessage {
         sctx: &mut sctx::SecurityContext,
         target: String,
         id: Option<i32>,
-    ) -> Result<Vec<WOBMessage>, Box<dyn std::error::Error>> {
-        if id.is_some() {
+    ) -> Result<Vec<WOBMessage>> {
+        if let Some(id) = id {
             let query = "CALL get_wob_message_for_target_by_id (?, ?)";
-            let rows = sqlx::query(query)
-                .bind(target)
-                .bind(id.unwrap())
-                .fetch_all(&sctx.pool)
-                .await?;
+            let rows = retry!(
+                sctx.retry_policy.non_idempotent(),
+                sqlx::query(query)
+                    .bind(&target)
+                    .bind(id)
+                    .fetch_all(&sctx.pool)
+                    .await
+            )?;
             debug_println!("rows: {:?}", rows);
             let mut messages = Vec::new();
             for row in rows {
@@ -615,10 +979,10 @@ impl WOBMessage {
             Ok(messages)
         } else {
             let query = "CALL get_wob_message (?)";
-            let rows = sqlx::query(query)
-                .bind(target)
-                .fetch_all(&sctx.pool)
-                .await?;
+            let rows = retry!(
+                sctx.retry_policy.non_idempotent(),
+                sqlx::query(query).bind(&target).fetch_all(&sctx.pool).await
+            )?;
             let mut messages = Vec::new();
             for row in rows {
                 messages.push(WOBMessage {
@@ -0,0 +1,208 @@
+use super::*;
+use sqlx::mysql::{MySql, MySqlArguments};
+use sqlx::{Arguments, Encode, Type};
+use std::marker::PhantomData;
+
+/// Typed SELECT builder over the `v_<table_name>` view of an [`ORMObject`].
+///
+/// Column names are validated as plain identifiers and every value is sent as a
+/// bound parameter, so nothing caller supplied is interpolated into the SQL text.
+pub struct Query<T: ORMObject> {
+    conditions: Vec<String>,
+    order: Vec<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    arguments: MySqlArguments,
+    error: Option<Error>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+pub fn query<T: ORMObject>() -> Query<T> {
+    Query {
+        conditions: Vec::new(),
+        order: Vec::new(),
+        limit: None,
+        offset: None,
+        arguments: MySqlArguments::default(),
+        error: None,
+        _marker: PhantomData,
+    }
+}
+
+fn is_identifier(column: &str) -> bool {
+    !column.is_empty()
+        && column
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl<T: ORMObject> Query<T> {
+    pub fn filter_eq<V>(self, column: &str, value: V) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        self.filter_op(column, "=", value)
+    }
+
+    pub fn filter_ne<V>(self, column: &str, value: V) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        self.filter_op(column, "<>", value)
+    }
+
+    pub fn filter_lt<V>(self, column: &str, value: V) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        self.filter_op(column, "<", value)
+    }
+
+    pub fn filter_gt<V>(self, column: &str, value: V) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        self.filter_op(column, ">", value)
+    }
+
+    pub fn filter_in<V>(mut self, column: &str, values: Vec<V>) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        if !self.check_column(column) {
+            return self;
+        }
+        if values.is_empty() {
+            // IN () is a syntax error in MySQL, an empty set matches nothing
+            self.conditions.push("FALSE".to_string());
+            return self;
+        }
+        let placeholders = vec!["?"; values.len()].join(", ");
+        for value in values {
+            self.bind(value);
+        }
+        self.conditions
+            .push(format!("`{}` IN ({})", column, placeholders));
+        self
+    }
+
+    pub fn filter_is_null(mut self, column: &str) -> Self {
+        if self.check_column(column) {
+            self.conditions.push(format!("`{}` IS NULL", column));
+        }
+        self
+    }
+
+    pub fn order_by(mut self, column: &str) -> Self {
+        if self.check_column(column) {
+            self.order.push(format!("`{}` ASC", column));
+        }
+        self
+    }
+
+    pub fn order_by_desc(mut self, column: &str) -> Self {
+        if self.check_column(column) {
+            self.order.push(format!("`{}` DESC", column));
+        }
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the SQL that will be sent to the server.
+    pub fn to_sql(&self) -> String {
+        let mut sql = format!("SELECT * FROM v_{}", T::table_name());
+        if !self.conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&self.conditions.join(" AND "));
+        }
+        if !self.order.is_empty() {
+            sql.push_str(" ORDER BY ");
+            sql.push_str(&self.order.join(", "));
+        }
+        match (self.limit, self.offset) {
+            (Some(limit), Some(offset)) => {
+                sql.push_str(&format!(" LIMIT {} OFFSET {}", limit, offset))
+            }
+            (Some(limit), None) => sql.push_str(&format!(" LIMIT {}", limit)),
+            // MySQL has no OFFSET without LIMIT, use the largest row count it accepts
+            (None, Some(offset)) => sql.push_str(&format!(" LIMIT {} OFFSET {}", u64::MAX, offset)),
+            (None, None) => {}
+        }
+        sql
+    }
+
+    /// Runs the query on `sctx`, which may be a context or a transaction.
+    pub async fn fetch_all(self, sctx: &mut impl SecurityExecutor) -> Result<Vec<T>> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let query = self.to_sql();
+        debug_println!("Query: {}", query);
+
+        let rows = retry!(
+            sctx.retry_policy(),
+            async {
+                let mut conn = sctx.connection().await?;
+                Ok::<_, Error>(
+                    sqlx::query_with(&query, self.arguments.clone())
+                        .fetch_all(&mut *conn)
+                        .await?,
+                )
+            }
+            .await
+        )?;
+        rows.into_iter().map(T::try_from_row).collect()
+    }
+
+    fn filter_op<V>(mut self, column: &str, op: &str, value: V) -> Self
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        if self.check_column(column) {
+            self.bind(value);
+            self.conditions.push(format!("`{}` {} ?", column, op));
+        }
+        self
+    }
+
+    fn check_column(&mut self, column: &str) -> bool {
+        if is_identifier(column) {
+            return true;
+        }
+        if self.error.is_none() {
+            self.error = Some(Error::InvalidArgument(format!(
+                "Invalid column name: {:?}",
+                column
+            )));
+        }
+        false
+    }
+
+    fn bind<V>(&mut self, value: V)
+    where
+        V: 'static + Encode<'static, MySql> + Type<MySql> + Send,
+    {
+        if self.error.is_some() {
+            return;
+        }
+        if self.arguments.len() >= BIND_LIMIT {
+            self.error = Some(Error::InvalidArgument(format!(
+                "Query exceeds the limit of {} bound parameters",
+                BIND_LIMIT
+            )));
+            return;
+        }
+        if let Err(e) = self.arguments.add(value) {
+            self.error = Some(Error::InvalidArgument(e.to_string()));
+        }
+    }
+}
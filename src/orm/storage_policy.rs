@@ -19,13 +19,12 @@ impl StorageType {
     }
 }
 
-
 #[derive(Clone, Debug, PartialEq, PartialOrd, sqlx::Type, serde::Deserialize, serde::Serialize)]
 #[sqlx(type_name = "workflow_state", rename_all = "UPPERCASE")]
 pub enum WorkflowState {
     Unprovisioned,
     Unusable,
-    Ready
+    Ready,
 }
 
 impl ORMUpdatableFieldValue for WorkflowState {
@@ -40,7 +39,6 @@ impl WorkflowState {
     }
 }
 
-
 impl_orm_object!(
     StoragePolicy,
     "storage_policy",
@@ -0,0 +1,451 @@
+use crate::config;
+use crate::debug_println;
+use crate::error::{Error, Result};
+use crate::hashcookie::HashCookieToken;
+use crate::sctx::{PoolSettings, SecurityContext, TlsSettings};
+use crate::secret::Secret;
+use crate::token::ProxyToken;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits of a [`PoolManager`].
+#[derive(Clone, Debug)]
+pub struct PoolManagerSettings {
+    /// Connections allowed across all users. Every pool reserves
+    /// `max_connections_per_user` of them for as long as it is open.
+    pub max_total_connections: u32,
+    pub max_connections_per_user: u32,
+    /// Pools not handed out for this long and with no connection in use are closed.
+    pub idle_pool_timeout: Duration,
+    /// Settings of each per-user pool; `max_connections` and `min_connections`
+    /// are overridden by the manager.
+    pub pool: PoolSettings,
+    pub tls: TlsSettings,
+}
+
+impl Default for PoolManagerSettings {
+    fn default() -> Self {
+        PoolManagerSettings {
+            max_total_connections: 64,
+            max_connections_per_user: 2,
+            idle_pool_timeout: Duration::from_secs(5 * 60),
+            pool: PoolSettings::default(),
+            tls: TlsSettings::default(),
+        }
+    }
+}
+
+impl PoolManagerSettings {
+    /// Takes the pool and TLS settings from `config`, keeping the default limits.
+    pub fn new_from_config(config: &config::MirandaConfig) -> Result<PoolManagerSettings> {
+        Ok(PoolManagerSettings {
+            pool: PoolSettings::new_from_config(config),
+            tls: TlsSettings::new_from_config(config)?,
+            ..PoolManagerSettings::default()
+        })
+    }
+}
+
+/// A snapshot of a [`PoolManager`]'s pools and counters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    pub pools: usize,
+    /// Connections reserved by open pools, including replaced ones still in use,
+    /// and pools being created.
+    pub reserved_connections: u32,
+    pub open_connections: u32,
+    pub idle_connections: u32,
+    /// Contexts handed out from an existing pool.
+    pub hits: u64,
+    /// Contexts that needed a new pool.
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct ManagedPool {
+    sctx: SecurityContext,
+    /// Cloned into every context handed out, so the pool is in use for as long
+    /// as any of them is alive.
+    lease: Arc<()>,
+    last_used: Instant,
+}
+
+impl ManagedPool {
+    fn new(sctx: SecurityContext) -> ManagedPool {
+        ManagedPool {
+            sctx,
+            lease: Arc::new(()),
+            last_used: Instant::now(),
+        }
+    }
+
+    /// True while a handed out context or one of its connections is alive.
+    fn in_use(&self) -> bool {
+        let pool = &self.sctx.pool;
+        Arc::strong_count(&self.lease) > 1 || pool.size() > pool.num_idle() as u32
+    }
+}
+
+#[derive(Default)]
+struct ManagerState {
+    pools: HashMap<String, ManagedPool>,
+    // pools replaced by new credentials while still in use, closed once unused
+    retired: Vec<ManagedPool>,
+    // pools being connected, counted against the cap before they exist
+    pending: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl ManagerState {
+    fn reserved(&self, per_user: u32) -> u32 {
+        (self.pools.len() + self.retired.len() + self.pending) as u32 * per_user
+    }
+
+    /// Removes the retired pools nothing uses anymore.
+    fn take_unused_retired(&mut self) -> Vec<ManagedPool> {
+        let (unused, in_use) = std::mem::take(&mut self.retired)
+            .into_iter()
+            .partition(|managed| !managed.in_use());
+        self.retired = in_use;
+        unused
+    }
+}
+
+/// A pool being connected, counted in `ManagerState::pending` until released or
+/// dropped, so a caller cancelled mid-connect does not leak the reservation.
+struct PendingReservation<'a> {
+    state: &'a Mutex<ManagerState>,
+    released: bool,
+}
+
+impl PendingReservation<'_> {
+    fn release(mut self, state: &mut ManagerState) {
+        state.pending -= 1;
+        self.released = true;
+    }
+}
+
+impl Drop for PendingReservation<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.state.lock().unwrap().pending -= 1;
+        }
+    }
+}
+
+struct ManagerInner {
+    host: String,
+    port: i32,
+    database: String,
+    settings: PoolManagerSettings,
+    state: Mutex<ManagerState>,
+}
+
+/// Per-user connection pools for services acting on behalf of many users, keyed
+/// by database credentials and sharing one connection cap.
+///
+/// Contexts handed out are meant to be short lived, e.g. one per request: a pool
+/// is only evicted once every context handed out for it has been dropped, so
+/// long lived contexts keep their connections reserved.
+#[derive(Clone)]
+pub struct PoolManager {
+    inner: Arc<ManagerInner>,
+}
+
+impl std::fmt::Debug for PoolManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoolManager")
+            .field("host", &self.inner.host)
+            .field("port", &self.inner.port)
+            .field("database", &self.inner.database)
+            .field("settings", &self.inner.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PoolManager {
+    pub fn new(
+        host: &str,
+        port: &i32,
+        database: &str,
+        settings: PoolManagerSettings,
+    ) -> PoolManager {
+        PoolManager {
+            inner: Arc::new(ManagerInner {
+                host: host.to_string(),
+                port: *port,
+                database: database.to_string(),
+                settings,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Uses the server, pool and TLS settings of `config`; its user is ignored.
+    pub fn new_from_config(config: &config::MirandaConfig) -> Result<PoolManager> {
+        config.validate()?;
+        let port = config
+            .port
+            .parse::<i32>()
+            .map_err(|e| Error::Config(format!("Invalid port {:?}: {}", config.port, e)))?;
+        Ok(PoolManager::new(
+            &config.host,
+            &port,
+            &config.database,
+            PoolManagerSettings::new_from_config(config)?,
+        ))
+    }
+
+    pub fn settings(&self) -> &PoolManagerSettings {
+        &self.inner.settings
+    }
+
+    /// Returns a context for `username`, reusing its pool if one is open. Fails
+    /// with [`Error::Exhausted`] if a new pool is needed and every connection is
+    /// reserved by pools in use.
+    pub async fn context(
+        &self,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<SecurityContext> {
+        self.evict_idle().await;
+        let mut to_close = Vec::new();
+        let room = {
+            let mut state = self.inner.state.lock().unwrap();
+            if let Some(managed) = state.pools.get_mut(username) {
+                if &managed.sctx.auth_string[1] == password && !managed.sctx.pool.is_closed() {
+                    managed.last_used = Instant::now();
+                    let sctx = self.hand_out(managed);
+                    state.hits += 1;
+                    return Ok(sctx);
+                }
+                // a different password only replaces the pool once it has connected,
+                // so a stale or wrong one cannot tear down a working pool
+            }
+            state.misses += 1;
+            self.make_room(&mut state, &mut to_close).map(|()| {
+                state.pending += 1;
+                PendingReservation {
+                    state: &self.inner.state,
+                    released: false,
+                }
+            })
+        };
+        for pool in to_close {
+            pool.close().await;
+        }
+        let reservation = room?;
+
+        debug_println!("[pool] Creating pool for {}", username);
+        let created = self.connect(username, password).await;
+        let mut unused = Vec::new();
+        let handed_out = {
+            let mut state = self.inner.state.lock().unwrap();
+            reservation.release(&mut state);
+            let sctx = created?;
+            match state.pools.get_mut(username) {
+                // another call created the pool meanwhile, keep the one already handed out
+                Some(managed) if managed.sctx.auth_string[1] == sctx.auth_string[1] => {
+                    managed.last_used = Instant::now();
+                    unused.push(sctx.pool.clone());
+                    self.hand_out(managed)
+                }
+                _ => {
+                    let managed = ManagedPool::new(sctx);
+                    let handed_out = self.hand_out(&managed);
+                    // the pool of the old credentials, left for evict_idle while in use
+                    if let Some(stale) = state.pools.insert(username.to_string(), managed) {
+                        if stale.in_use() {
+                            state.retired.push(stale);
+                        } else {
+                            unused.push(stale.sctx.pool);
+                        }
+                    }
+                    handed_out
+                }
+            }
+        };
+        for pool in unused {
+            pool.close().await;
+        }
+        Ok(handed_out)
+    }
+
+    /// Returns a context for the user of a decrypted cookie token, see
+    /// [`SecurityContext::for_user`].
+    pub async fn context_for_user(&self, token: &HashCookieToken) -> Result<SecurityContext> {
+        if token.exp < chrono::Utc::now().timestamp() {
+            return Err(Error::TokenExpired);
+        }
+        let password = token
+            .dbauth
+            .as_ref()
+            .ok_or_else(|| Error::TokenMalformed("token has no dbauth".to_string()))?;
+        self.context(&token.username, password).await
+    }
+
+    pub async fn context_for_proxy_token(&self, token: &ProxyToken) -> Result<SecurityContext> {
+        self.context(&token.username(), token.password()).await
+    }
+
+    /// Closes the pools that have not been in use for longer than
+    /// [`PoolManagerSettings::idle_pool_timeout`], and the replaced pools no longer
+    /// in use, and returns how many were closed.
+    pub async fn evict_idle(&self) -> usize {
+        let timeout = self.inner.settings.idle_pool_timeout;
+        let evicted: Vec<ManagedPool> = {
+            let mut state = self.inner.state.lock().unwrap();
+            let mut evicted = state.take_unused_retired();
+            for managed in state.pools.values_mut() {
+                // idle time counts from the last check that found it in use
+                if managed.in_use() {
+                    managed.last_used = Instant::now();
+                }
+            }
+            let idle: Vec<String> = state
+                .pools
+                .iter()
+                .filter(|(_, managed)| managed.last_used.elapsed() >= timeout)
+                .map(|(username, _)| username.clone())
+                .collect();
+            evicted.extend(
+                idle.iter()
+                    .filter_map(|username| state.pools.remove(username)),
+            );
+            state.evictions += evicted.len() as u64;
+            evicted
+        };
+        for managed in &evicted {
+            debug_println!(
+                "[pool] Closing idle pool for {}",
+                managed.sctx.auth_string[0].expose()
+            );
+            managed.sctx.pool.close().await;
+        }
+        evicted.len()
+    }
+
+    /// Closes every pool.
+    pub async fn close(&self) {
+        let pools: Vec<ManagedPool> = {
+            let mut state = self.inner.state.lock().unwrap();
+            let retired = std::mem::take(&mut state.retired);
+            state
+                .pools
+                .drain()
+                .map(|(_, managed)| managed)
+                .chain(retired)
+                .collect()
+        };
+        for managed in pools {
+            managed.sctx.pool.close().await;
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.inner.state.lock().unwrap();
+        let per_user = self.inner.settings.max_connections_per_user;
+        let mut metrics = PoolMetrics {
+            pools: state.pools.len(),
+            reserved_connections: state.reserved(per_user),
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+            ..PoolMetrics::default()
+        };
+        for managed in state.pools.values().chain(&state.retired) {
+            metrics.open_connections += managed.sctx.pool.size();
+            metrics.idle_connections += managed.sctx.pool.num_idle() as u32;
+        }
+        metrics
+    }
+
+    /// Evicts least recently used pools not in use until a new pool fits under
+    /// the cap.
+    fn make_room(
+        &self,
+        state: &mut ManagerState,
+        to_close: &mut Vec<sqlx::Pool<sqlx::MySql>>,
+    ) -> Result<()> {
+        let settings = &self.inner.settings;
+        let per_user = settings.max_connections_per_user;
+        for retired in state.take_unused_retired() {
+            state.evictions += 1;
+            to_close.push(retired.sctx.pool);
+        }
+        loop {
+            let reserved = state.reserved(per_user);
+            if reserved + per_user <= settings.max_total_connections {
+                return Ok(());
+            }
+            let lru = state
+                .pools
+                .iter()
+                .filter(|(_, managed)| !managed.in_use())
+                .min_by_key(|(_, managed)| managed.last_used)
+                .map(|(username, _)| username.clone());
+            match lru.and_then(|username| state.pools.remove(&username)) {
+                Some(evicted) => {
+                    debug_println!(
+                        "[pool] Evicting pool for {}",
+                        evicted.sctx.auth_string[0].expose()
+                    );
+                    state.evictions += 1;
+                    to_close.push(evicted.sctx.pool);
+                }
+                None => {
+                    return Err(Error::Exhausted(format!(
+                        "all {} connections are reserved by pools in use",
+                        settings.max_total_connections
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn connect(&self, username: &str, password: &Secret<String>) -> Result<SecurityContext> {
+        let settings = &self.inner.settings;
+        let mut sctx = SecurityContext::new_with_settings(
+            username,
+            password.expose(),
+            &self.inner.host,
+            &self.inner.port,
+            &self.inner.database,
+            PoolSettings {
+                max_connections: settings.max_connections_per_user,
+                min_connections: 0,
+                ..settings.pool.clone()
+            },
+            settings.tls.clone(),
+        )
+        .await?;
+        sctx.renew_id().await?;
+        Ok(sctx)
+    }
+
+    /// Adds a pool for `sctx` as if it had been connected, and hands it out.
+    #[cfg(test)]
+    pub(crate) fn adopt(&self, sctx: SecurityContext) -> SecurityContext {
+        let managed = ManagedPool::new(sctx);
+        let handed_out = self.hand_out(&managed);
+        let username = managed.sctx.auth_string[0].expose().clone();
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .pools
+            .insert(username, managed);
+        handed_out
+    }
+
+    // the stored context has no manager, so pools and manager do not own each other
+    fn hand_out(&self, managed: &ManagedPool) -> SecurityContext {
+        let mut sctx = managed.sctx.clone();
+        sctx.set_pool_manager(Some(self.clone()));
+        sctx.set_lease(managed.lease.clone());
+        sctx
+    }
+}
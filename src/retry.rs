@@ -0,0 +1,126 @@
+use crate::debug_println;
+use crate::error::Error;
+use rand_core::{OsRng, RngCore};
+use sqlx::mysql::MySqlDatabaseError;
+use std::time::Duration;
+
+/// How failed database calls are retried. Only errors classified as retryable
+/// are retried: lost connections and pool timeouts (unless
+/// `retry_connection_errors` is off) and the MySQL error numbers in
+/// `retryable_mysql_errors`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every further attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomize each delay between half and all of the backoff, so that
+    /// clients failing together do not retry together.
+    pub jitter: bool,
+    /// Server errors after which the statement was rolled back.
+    pub retryable_mysql_errors: Vec<u16>,
+    /// Retry I/O and pool errors. After a lost connection the server
+    /// may already have run the statement.
+    pub retry_connection_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            jitter: true,
+            // 1205: lock wait timeout, 1213: deadlock
+            retryable_mysql_errors: vec![1205, 1213],
+            retry_connection_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries, used inside transactions where the server has
+    /// already rolled back the work done before the failure.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// This policy restricted to errors the server rolled back, for calls that
+    /// must not run twice such as sending or consuming messages.
+    pub fn non_idempotent(&self) -> Self {
+        RetryPolicy {
+            retry_connection_errors: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_retryable(&self, e: &Error) -> bool {
+        let (sqlx_error, transient) = match e {
+            Error::Transient(e) => (e, true),
+            Error::Database(e) => (e, false),
+            _ => return false,
+        };
+        match sqlx_error {
+            sqlx::Error::Database(db) => match db.try_downcast_ref::<MySqlDatabaseError>() {
+                Some(db) => self.retryable_mysql_errors.contains(&db.number()),
+                None => transient && self.retry_connection_errors,
+            },
+            _ => transient && self.retry_connection_errors,
+        }
+    }
+
+    /// Returns true if the call that failed on attempt number `attempt` (starting
+    /// at 1) should be tried again.
+    pub fn should_retry(&self, e: &Error, attempt: u32) -> bool {
+        attempt < self.max_attempts && self.is_retryable(e)
+    }
+
+    /// The delay after failed attempt number `attempt`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        let fraction = 0.5 + (OsRng.next_u32() as f64 / u32::MAX as f64) / 2.0;
+        backoff.mul_f64(fraction)
+    }
+
+    pub(crate) async fn wait(&self, attempt: u32, e: &Error) {
+        let backoff = self.backoff(attempt);
+        debug_println!(
+            "[retry] Attempt {} failed ({}), retrying in {:?}",
+            attempt,
+            e,
+            backoff
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Evaluates `$body` (an expression yielding a `Result` whose error converts into
+/// [`Error`]) until it succeeds, fails with an error that is not retryable, or
+/// `$policy` runs out of attempts.
+macro_rules! retry {
+    ($policy:expr, $body:expr) => {{
+        let policy: $crate::retry::RetryPolicy = $policy;
+        let mut attempt = 1;
+        loop {
+            match ($body).map_err($crate::error::Error::from) {
+                Err(e) if policy.should_retry(&e, attempt) => {
+                    policy.wait(attempt, &e).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        }
+    }};
+}
+pub(crate) use retry;
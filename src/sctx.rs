@@ -1,18 +1,277 @@
 use crate::config;
 use crate::debug_println;
+use crate::error::{Error, Result};
+use crate::hashcookie::HashCookieToken;
+use crate::pool::PoolManager;
+use crate::retry::RetryPolicy;
+use crate::secret::Secret;
+use crate::token::ProxyToken;
 use sqlx::mysql::MySqlConnection;
-use sqlx::mysql::MySqlPoolOptions;
-use sqlx::{Connection, Row};
+use sqlx::mysql::{MySqlConnectOptions, MySqlPoolOptions, MySqlSslMode};
+use sqlx::pool::PoolConnection;
+use sqlx::{Connection, MySql, Row, Transaction};
+use std::collections::VecDeque;
 use std::env;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
 
+/// Connection pool tuning, see the matching fields of [`config::MirandaConfig`].
 #[derive(Clone, Debug)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// Ping connections that were idle for longer than this before handing them out.
+    pub ping_after_idle: Duration,
+    pub test_before_acquire: bool,
+    pub statement_cache_capacity: usize,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_connections: 1,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            ping_after_idle: Duration::from_secs(60),
+            test_before_acquire: false,
+            statement_cache_capacity: 100,
+        }
+    }
+}
+
+impl PoolSettings {
+    pub fn new_from_config(config: &config::MirandaConfig) -> PoolSettings {
+        let defaults = PoolSettings::default();
+        // a timeout of 0 disables idle_timeout and max_lifetime
+        let optional_secs = |secs: Option<u64>, default: Option<Duration>| match secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => default,
+        };
+        PoolSettings {
+            max_connections: config
+                .max_pool_connections
+                .unwrap_or(defaults.max_connections),
+            min_connections: config
+                .min_pool_connections
+                .unwrap_or(defaults.min_connections),
+            acquire_timeout: config
+                .acquire_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.acquire_timeout),
+            idle_timeout: optional_secs(config.idle_timeout_secs, defaults.idle_timeout),
+            max_lifetime: optional_secs(config.max_lifetime_secs, defaults.max_lifetime),
+            ping_after_idle: config
+                .ping_after_idle_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.ping_after_idle),
+            test_before_acquire: config
+                .test_before_acquire
+                .unwrap_or(defaults.test_before_acquire),
+            statement_cache_capacity: config
+                .statement_cache_capacity
+                .unwrap_or(defaults.statement_cache_capacity),
+        }
+    }
+}
+
+/// TLS for the database connection, see the matching fields of [`config::MirandaConfig`].
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    pub ssl_mode: MySqlSslMode,
+    /// CA certificate the server certificate must chain to. Replaces the built-in
+    /// root certificates.
+    pub ssl_ca: Option<PathBuf>,
+    /// Client certificate and private key, both or neither.
+    pub ssl_cert: Option<PathBuf>,
+    pub ssl_key: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    pub fn new_from_config(config: &config::MirandaConfig) -> Result<TlsSettings> {
+        let ssl_mode = match &config.ssl_mode {
+            Some(mode) => MySqlSslMode::from_str(mode)
+                .map_err(|_| Error::Config(format!("Invalid ssl_mode {:?}", mode)))?,
+            None => MySqlSslMode::default(),
+        };
+        if config.ssl_cert.is_some() != config.ssl_key.is_some() {
+            return Err(Error::Config(
+                "ssl_cert and ssl_key must be set together".to_string(),
+            ));
+        }
+        Ok(TlsSettings {
+            ssl_mode,
+            ssl_ca: config.ssl_ca.as_ref().map(PathBuf::from),
+            ssl_cert: config.ssl_cert.as_ref().map(PathBuf::from),
+            ssl_key: config.ssl_key.as_ref().map(PathBuf::from),
+        })
+    }
+
+    pub fn apply(&self, mut options: MySqlConnectOptions) -> MySqlConnectOptions {
+        options = options.ssl_mode(self.ssl_mode);
+        if let Some(ca) = &self.ssl_ca {
+            options = options.ssl_ca(ca);
+        }
+        if let (Some(cert), Some(key)) = (&self.ssl_cert, &self.ssl_key) {
+            options = options.ssl_client_cert(cert).ssl_client_key(key);
+        }
+        options
+    }
+
+    /// The equivalent options for `mysql_async`. It cannot fall back to plain text,
+    /// so PREFERRED connects without TLS like DISABLED does.
+    pub fn mysql_async_ssl_opts(&self) -> Option<mysql_async::SslOpts> {
+        let opts = match self.ssl_mode {
+            MySqlSslMode::Disabled | MySqlSslMode::Preferred => return None,
+            MySqlSslMode::Required => mysql_async::SslOpts::default()
+                .with_danger_accept_invalid_certs(true)
+                .with_danger_skip_domain_validation(true),
+            MySqlSslMode::VerifyCa => {
+                mysql_async::SslOpts::default().with_danger_skip_domain_validation(true)
+            }
+            MySqlSslMode::VerifyIdentity => mysql_async::SslOpts::default(),
+        };
+        let opts = match &self.ssl_ca {
+            Some(ca) => opts
+                .with_root_certs(vec![ca.clone().into()])
+                .with_disable_built_in_roots(true),
+            None => opts,
+        };
+        let identity = match (&self.ssl_cert, &self.ssl_key) {
+            (Some(cert), Some(key)) => Some(mysql_async::ClientIdentity::new(
+                cert.clone().into(),
+                key.clone().into(),
+            )),
+            _ => None,
+        };
+        Some(opts.with_client_identity(identity))
+    }
+}
+
+/// Limits of the per-user contexts created by [`SecurityContext::for_user`].
+#[derive(Clone, Debug)]
+pub struct UserPoolSettings {
+    /// Number of user contexts kept; the least recently used one is dropped first.
+    pub max_users: usize,
+    pub max_connections_per_user: u32,
+}
+
+impl Default for UserPoolSettings {
+    fn default() -> Self {
+        UserPoolSettings {
+            max_users: 32,
+            max_connections_per_user: 2,
+        }
+    }
+}
+
+struct CachedUserContext {
+    sctx: SecurityContext,
+    last_used: u64,
+}
+
+/// User contexts created from an admin context, shared by all its clones.
+#[derive(Default)]
+pub(crate) struct UserContextCache {
+    settings: UserPoolSettings,
+    entries: Vec<CachedUserContext>,
+    clock: u64,
+}
+
+impl UserContextCache {
+    pub(crate) fn new(settings: UserPoolSettings) -> UserContextCache {
+        UserContextCache {
+            settings,
+            ..UserContextCache::default()
+        }
+    }
+
+    pub(crate) fn get(
+        &mut self,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Option<SecurityContext> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.sctx.auth_string[0].expose() == username)?;
+        if &self.entries[index].sctx.auth_string[1] != password {
+            // a wrong or changed password must not evict a working pool, the entry is
+            // replaced by insert once the new credentials have connected
+            return None;
+        }
+        self.clock += 1;
+        self.entries[index].last_used = self.clock;
+        Some(self.entries[index].sctx.clone())
+    }
+
+    /// Caches `sctx`, replacing any context of the same user.
+    pub(crate) fn insert(&mut self, sctx: SecurityContext) {
+        self.entries
+            .retain(|entry| entry.sctx.auth_string[0] != sctx.auth_string[0]);
+        while !self.entries.is_empty() && self.entries.len() >= self.settings.max_users {
+            let lru = (0..self.entries.len())
+                .min_by_key(|&i| self.entries[i].last_used)
+                .unwrap_or(0);
+            let evicted = self.entries.swap_remove(lru);
+            debug_println!(
+                "[sctx] Evicting user context for {}",
+                evicted.sctx.auth_string[0].expose()
+            );
+        }
+        if self.settings.max_users == 0 {
+            return;
+        }
+        self.clock += 1;
+        self.entries.push(CachedUserContext {
+            sctx,
+            last_used: self.clock,
+        });
+    }
+}
+
+#[derive(Clone)]
 pub struct SecurityContext {
     pub user_id: i32,
-    pub auth_string: [String; 2],
+    pub auth_string: [Secret<String>; 2],
     pub pool: sqlx::Pool<sqlx::MySql>,
     pub is_admin: bool,
-    pub constr: String,
+    pub retry_policy: RetryPolicy,
+    // both hold the password, keep them out of Debug
+    pub(crate) connect_options: MySqlConnectOptions,
+    cdc_options: mysql_async::Opts,
+    pool_settings: PoolSettings,
+    tls: TlsSettings,
+    users: Arc<Mutex<UserContextCache>>,
+    manager: Option<PoolManager>,
+    // held by contexts handed out by a PoolManager, which keeps their pool open
+    lease: Option<Arc<()>>,
+}
+
+impl std::fmt::Debug for SecurityContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecurityContext")
+            .field("user_id", &self.user_id)
+            .field("username", self.auth_string[0].expose())
+            .field("is_admin", &self.is_admin)
+            .field("host", &self.connect_options.get_host())
+            .field("port", &self.connect_options.get_port())
+            .field("database", &self.connect_options.get_database())
+            .field("retry_policy", &self.retry_policy)
+            .finish_non_exhaustive()
+    }
 }
 
 impl SecurityContext {
@@ -23,18 +282,63 @@ impl SecurityContext {
         port: &i32,
         database: &str,
         max_pool_connections: u32,
-    ) -> Result<SecurityContext, Box<dyn std::error::Error>> {
-        let connstr = format!(
-            "mysql://{}:{}@{}:{}/{}",
-            username, password, host, port, database
-        );
+    ) -> Result<SecurityContext> {
+        let settings = PoolSettings {
+            max_connections: max_pool_connections,
+            ..PoolSettings::default()
+        };
+        SecurityContext::new_with_pool_settings(username, password, host, port, database, settings)
+            .await
+    }
+
+    pub async fn new_with_pool_settings(
+        username: &str,
+        password: &str,
+        host: &str,
+        port: &i32,
+        database: &str,
+        settings: PoolSettings,
+    ) -> Result<SecurityContext> {
+        SecurityContext::new_with_settings(
+            username,
+            password,
+            host,
+            port,
+            database,
+            settings,
+            TlsSettings::default(),
+        )
+        .await
+    }
+
+    pub async fn new_with_settings(
+        username: &str,
+        password: &str,
+        host: &str,
+        port: &i32,
+        database: &str,
+        settings: PoolSettings,
+        tls: TlsSettings,
+    ) -> Result<SecurityContext> {
+        let port =
+            u16::try_from(*port).map_err(|_| Error::Config(format!("Invalid port {}", port)))?;
+        let (connect_options, cdc_options) =
+            SecurityContext::options(username, password, host, port, database, &settings, &tls);
+        let ping_after_idle = settings.ping_after_idle;
         let pool = match MySqlPoolOptions::new()
-            .max_connections(max_pool_connections)
-            .test_before_acquire(false)
-            .before_acquire(|conn, meta| {
+            .max_connections(settings.max_connections)
+            .min_connections(settings.min_connections)
+            .acquire_timeout(settings.acquire_timeout)
+            .idle_timeout(settings.idle_timeout)
+            .max_lifetime(settings.max_lifetime)
+            .test_before_acquire(settings.test_before_acquire)
+            .before_acquire(move |conn, meta| {
                 Box::pin(async move {
-                    if meta.idle_for.as_secs() > 60 {
-                        debug_println!("[sctx] Idle for more than 15 seconds, checking connection");
+                    if meta.idle_for > ping_after_idle {
+                        debug_println!(
+                            "[sctx] Idle for more than {:?}, checking connection",
+                            ping_after_idle
+                        );
                         let res = conn.ping().await;
                         match res {
                             Ok(_) => {
@@ -50,7 +354,7 @@ impl SecurityContext {
                     Ok(true)
                 })
             })
-            .connect(&connstr)
+            .connect_with(connect_options.clone())
             .await
         {
             Ok(pool) => pool,
@@ -59,37 +363,210 @@ impl SecurityContext {
                 return Err(e.into());
             }
         };
-        let auth_string = [username.to_string(), password.to_string()];
+        let auth_string = [Secret::from(username), Secret::from(password)];
         Ok(SecurityContext {
             user_id: -1,
             auth_string,
             pool,
             is_admin: false,
-            constr: connstr,
+            retry_policy: RetryPolicy::default(),
+            connect_options,
+            cdc_options,
+            pool_settings: settings,
+            tls,
+            users: Arc::default(),
+            manager: None,
+            lease: None,
         })
     }
 
+    // credentials are passed as fields, never through a URL, so they need no escaping
+    fn options(
+        username: &str,
+        password: &str,
+        host: &str,
+        port: u16,
+        database: &str,
+        settings: &PoolSettings,
+        tls: &TlsSettings,
+    ) -> (MySqlConnectOptions, mysql_async::Opts) {
+        let connect_options = tls
+            .apply(
+                MySqlConnectOptions::new()
+                    .host(host)
+                    .port(port)
+                    .username(username)
+                    .password(password)
+                    .database(database),
+            )
+            .statement_cache_capacity(settings.statement_cache_capacity);
+        let cdc_options = mysql_async::OptsBuilder::default()
+            .ip_or_hostname(host)
+            .tcp_port(port)
+            .user(Some(username))
+            .pass(Some(password))
+            .db_name(Some(database))
+            .ssl_opts(tls.mysql_async_ssl_opts())
+            .into();
+        (connect_options, cdc_options)
+    }
+
+    /// A context for `username` whose pool only connects when first used, to a
+    /// port nothing listens on, so every query fails quickly.
+    #[cfg(test)]
+    pub(crate) fn new_lazy(username: &str, password: &str) -> SecurityContext {
+        let pool_settings = PoolSettings::default();
+        let tls = TlsSettings::default();
+        let (connect_options, cdc_options) = SecurityContext::options(
+            username,
+            password,
+            "127.0.0.1",
+            1,
+            "miranda",
+            &pool_settings,
+            &tls,
+        );
+        SecurityContext {
+            user_id: -1,
+            auth_string: [Secret::from(username), Secret::from(password)],
+            pool: MySqlPoolOptions::new()
+                .acquire_timeout(Duration::from_millis(200))
+                .connect_lazy_with(connect_options.clone()),
+            is_admin: false,
+            retry_policy: RetryPolicy::default(),
+            connect_options,
+            cdc_options,
+            pool_settings,
+            tls,
+            users: Arc::default(),
+            manager: None,
+            lease: None,
+        }
+    }
+
     pub fn set_admin(&mut self, is_admin: bool) {
         self.is_admin = is_admin;
     }
 
-    pub async fn new_from_config(
-        config: config::MirandaConfig,
-    ) -> Result<SecurityContext, Box<dyn std::error::Error>> {
-        let port = config.port.parse::<i32>()?;
-        let sc = SecurityContext::new(
+    /// The manager this context's pool belongs to, if it was handed out by one.
+    pub fn pool_manager(&self) -> Option<&PoolManager> {
+        self.manager.as_ref()
+    }
+
+    /// Makes [`SecurityContext::for_user`] take user contexts from `manager`
+    /// instead of creating a pool per user.
+    pub fn set_pool_manager(&mut self, manager: Option<PoolManager>) {
+        self.manager = manager;
+    }
+
+    pub(crate) fn set_lease(&mut self, lease: Arc<()>) {
+        self.lease = Some(lease);
+    }
+
+    /// Sets the limits of the contexts created by [`SecurityContext::for_user`] and
+    /// drops the ones cached so far.
+    pub fn set_user_pool_settings(&mut self, settings: UserPoolSettings) {
+        *self.users.lock().unwrap() = UserContextCache::new(settings);
+    }
+
+    /// Returns a context for the user a decrypted cookie token belongs to, logging
+    /// in with the token's `dbauth`. `admin_ctx` must be an admin context; its
+    /// server, TLS and pool settings are used, with the pool bounded by
+    /// [`UserPoolSettings`]. Contexts are cached per user, so repeated calls share
+    /// one pool. If `admin_ctx` has a [`PoolManager`], the context comes from it.
+    pub async fn for_user(
+        admin_ctx: &SecurityContext,
+        token: &HashCookieToken,
+    ) -> Result<SecurityContext> {
+        if token.exp < chrono::Utc::now().timestamp() {
+            return Err(Error::TokenExpired);
+        }
+        let password = token
+            .dbauth
+            .as_ref()
+            .ok_or_else(|| Error::TokenMalformed("token has no dbauth".to_string()))?;
+        SecurityContext::for_credentials(admin_ctx, &token.username, password).await
+    }
+
+    /// Like [`SecurityContext::for_user`], for a proxy account.
+    pub async fn for_proxy_token(
+        admin_ctx: &SecurityContext,
+        token: &ProxyToken,
+    ) -> Result<SecurityContext> {
+        SecurityContext::for_credentials(admin_ctx, &token.username(), token.password()).await
+    }
+
+    async fn for_credentials(
+        admin_ctx: &SecurityContext,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<SecurityContext> {
+        if !admin_ctx.is_admin {
+            return Err(Error::PermissionDenied(
+                "only an admin context can create user contexts".to_string(),
+            ));
+        }
+        if let Some(manager) = &admin_ctx.manager {
+            return manager.context(username, password).await;
+        }
+        let cached = admin_ctx.users.lock().unwrap().get(username, password);
+        if let Some(sctx) = cached {
+            return Ok(sctx);
+        }
+
+        debug_println!("[sctx] Creating user context for {}", username);
+        let options = &admin_ctx.connect_options;
+        let max_connections = admin_ctx
+            .users
+            .lock()
+            .unwrap()
+            .settings
+            .max_connections_per_user;
+        let settings = PoolSettings {
+            max_connections,
+            min_connections: 0,
+            ..admin_ctx.pool_settings.clone()
+        };
+        let mut sctx = SecurityContext::new_with_settings(
+            username,
+            password.expose(),
+            options.get_host(),
+            &i32::from(options.get_port()),
+            options.get_database().unwrap_or_default(),
+            settings,
+            admin_ctx.tls.clone(),
+        )
+        .await?;
+        sctx.retry_policy = admin_ctx.retry_policy.clone();
+        sctx.renew_id().await?;
+        admin_ctx.users.lock().unwrap().insert(sctx.clone());
+        Ok(sctx)
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub async fn new_from_config(config: config::MirandaConfig) -> Result<SecurityContext> {
+        config.validate()?;
+        let port = config
+            .port
+            .parse::<i32>()
+            .map_err(|e| Error::Config(format!("Invalid port {:?}: {}", config.port, e)))?;
+        let sc = SecurityContext::new_with_settings(
             &config.user,
-            &config.password,
+            config.password.expose(),
             &config.host,
             &port,
             &config.database,
-            config.max_pool_connections.unwrap_or(1),
+            PoolSettings::new_from_config(&config),
+            TlsSettings::new_from_config(&config)?,
         )
         .await?;
         Ok(sc)
     }
 
-    pub async fn extend_proxy_account_claim(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn extend_proxy_account_claim(&mut self) -> Result<()> {
         let app_name = env::var("MIRANDA_APPLICATION").unwrap_or_else(|_| "mirmod-rs".to_string());
         debug_println!("[sctx] Extending proxy account claim for {}", app_name);
         let row = sqlx::query("CALL sp_extend_proxy_account_claim(?)")
@@ -106,7 +583,49 @@ impl SecurityContext {
         }
     }
 
-    pub async fn renew_id(&mut self) -> Result<i32, Box<dyn std::error::Error>> {
+    /// Extends the proxy account claim now and then every `interval` on a tokio
+    /// task, until the returned handle is dropped or stopped. Failed extensions
+    /// are reported through [`ClaimKeepalive::next_failure`] and retried at the
+    /// next interval.
+    pub fn spawn_claim_keepalive(&self, interval: Duration) -> Result<ClaimKeepalive> {
+        if !ProxyToken::is_proxy_user(self.auth_string[0].expose()) {
+            return Err(Error::InvalidArgument(format!(
+                "{} is not a proxy account",
+                self.auth_string[0].expose()
+            )));
+        }
+        if interval.is_zero() {
+            return Err(Error::InvalidArgument(
+                "Claim keepalive interval must not be zero".to_string(),
+            ));
+        }
+        let mut sctx = self.clone();
+        let failures = Arc::new(FailureQueue::default());
+        let failure_tx = FailureSender(failures.clone());
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    // fires on stop() and when the handle is dropped
+                    _ = &mut stopped => break,
+                    _ = ticks.tick() => {}
+                }
+                if let Err(e) = sctx.extend_proxy_account_claim().await {
+                    failure_tx.send(e);
+                }
+            }
+            debug_println!("[sctx] Claim keepalive stopped");
+        });
+        Ok(ClaimKeepalive {
+            failures,
+            stop: Some(stop),
+            task: Some(task),
+        })
+    }
+
+    pub async fn renew_id(&mut self) -> Result<i32> {
         debug_println!("[sctx] Renewing id");
         if self.is_admin {
             return Ok(-1);
@@ -118,7 +637,7 @@ impl SecurityContext {
             Ok(Some(row)) => {
                 self.user_id = row.get::<i32, &str>("id");
                 debug_println!("[sctx] id={}", self.user_id);
-                if self.auth_string[0].starts_with("pxy.") {
+                if ProxyToken::is_proxy_user(self.auth_string[0].expose()) {
                     let claim = self.extend_proxy_account_claim().await;
                     match claim {
                         Ok(_) => Ok(self.user_id),
@@ -130,16 +649,199 @@ impl SecurityContext {
             }
             Ok(None) => {
                 self.user_id = -1;
-                Err("No user found".into())
+                Err(Error::NotFound("No user found".to_string()))
             }
             Err(e) => Err(e.into()),
         }
     }
 
-    pub async fn create_single_connection(
-        &self,
-    ) -> std::result::Result<MySqlConnection, sqlx::Error> {
+    /// Starts a transaction on the pool. ORM calls given the returned handle
+    /// instead of the context are committed or rolled back together; dropping the
+    /// handle without calling `commit` rolls back.
+    pub async fn begin(&self) -> Result<SecurityTransaction> {
+        let tx = self.pool.begin().await?;
+        Ok(SecurityTransaction {
+            user_id: self.user_id,
+            is_admin: self.is_admin,
+            tx,
+        })
+    }
+
+    pub async fn create_single_connection(&self) -> Result<MySqlConnection> {
         // create a connection without using a pool
-        MySqlConnection::connect(&self.constr).await
+        Ok(MySqlConnection::connect_with(&self.connect_options).await?)
+    }
+
+    /// Options for a `mysql_async` connection to the same server as the pool.
+    pub(crate) fn cdc_options(&self) -> mysql_async::Opts {
+        self.cdc_options.clone()
+    }
+}
+
+/// Failures kept for [`ClaimKeepalive::next_failure`]; older ones are dropped.
+const CLAIM_KEEPALIVE_FAILURES: usize = 16;
+
+/// Failed extensions not read yet, bounded so it does not grow while nobody reads it.
+#[derive(Default)]
+struct FailureQueue {
+    failures: Mutex<VecDeque<Error>>,
+    // set once the task has exited, however it exited
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+impl FailureQueue {
+    fn pop(&self) -> Option<Error> {
+        self.failures.lock().unwrap().pop_front()
+    }
+}
+
+/// The task's end of the [`FailureQueue`], closing it when dropped.
+struct FailureSender(Arc<FailureQueue>);
+
+impl FailureSender {
+    fn send(&self, e: Error) {
+        let mut failures = self.0.failures.lock().unwrap();
+        if failures.len() >= CLAIM_KEEPALIVE_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(e);
+        drop(failures);
+        self.0.notify.notify_one();
+    }
+}
+
+impl Drop for FailureSender {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.notify.notify_one();
+    }
+}
+
+/// Handle of the task started by [`SecurityContext::spawn_claim_keepalive`].
+/// Dropping it stops the task.
+pub struct ClaimKeepalive {
+    failures: Arc<FailureQueue>,
+    // dropping the sender wakes the task, which exits between extensions
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ClaimKeepalive {
+    /// Waits for the next failed extension. Returns None once the task has stopped.
+    pub async fn next_failure(&mut self) -> Option<Error> {
+        loop {
+            // read before popping, so a failure queued just before closing is not lost
+            let closed = self.failures.closed.load(Ordering::Acquire);
+            if let Some(e) = self.failures.pop() {
+                return Some(e);
+            }
+            if closed {
+                return None;
+            }
+            self.failures.notify.notified().await;
+        }
+    }
+
+    /// Returns a failed extension if one happened since the last call.
+    pub fn try_next_failure(&mut self) -> Option<Error> {
+        self.failures.pop()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.task.as_ref().is_some_and(|task| !task.is_finished())
+    }
+
+    /// Stops the task, waiting for an extension in progress to finish.
+    pub async fn stop(mut self) {
+        self.stop.take();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+pub struct SecurityTransaction {
+    pub user_id: i32,
+    pub is_admin: bool,
+    tx: Transaction<'static, MySql>,
+}
+
+impl SecurityTransaction {
+    pub async fn commit(self) -> Result<()> {
+        debug_println!("[sctx] Committing transaction");
+        Ok(self.tx.commit().await?)
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        debug_println!("[sctx] Rolling back transaction");
+        Ok(self.tx.rollback().await?)
+    }
+}
+
+/// A connection handed out by a [`SecurityExecutor`], either checked out of the
+/// pool or borrowed from an open transaction.
+pub enum ExecutorConnection<'c> {
+    Pooled(PoolConnection<MySql>),
+    Borrowed(&'c mut MySqlConnection),
+}
+
+impl Deref for ExecutorConnection<'_> {
+    type Target = MySqlConnection;
+
+    fn deref(&self) -> &MySqlConnection {
+        match self {
+            ExecutorConnection::Pooled(conn) => conn,
+            ExecutorConnection::Borrowed(conn) => conn,
+        }
+    }
+}
+
+impl DerefMut for ExecutorConnection<'_> {
+    fn deref_mut(&mut self) -> &mut MySqlConnection {
+        match self {
+            ExecutorConnection::Pooled(conn) => conn,
+            ExecutorConnection::Borrowed(conn) => conn,
+        }
+    }
+}
+
+/// Implemented by [`SecurityContext`] and [`SecurityTransaction`] so ORM functions
+/// can run either directly on the pool or inside a transaction. Also implemented
+/// for a transaction begun on an executor's connection, e.g. a savepoint.
+pub trait SecurityExecutor: Send {
+    fn connection(&mut self) -> impl Future<Output = Result<ExecutorConnection<'_>>> + Send;
+    fn retry_policy(&self) -> RetryPolicy;
+}
+
+impl SecurityExecutor for SecurityContext {
+    async fn connection(&mut self) -> Result<ExecutorConnection<'_>> {
+        Ok(ExecutorConnection::Pooled(self.pool.acquire().await?))
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+}
+
+impl SecurityExecutor for SecurityTransaction {
+    async fn connection(&mut self) -> Result<ExecutorConnection<'_>> {
+        Ok(ExecutorConnection::Borrowed(&mut self.tx))
+    }
+
+    // a failed statement may have rolled back the whole transaction, so retrying
+    // it alone is never safe
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
+
+impl SecurityExecutor for Transaction<'_, MySql> {
+    async fn connection(&mut self) -> Result<ExecutorConnection<'_>> {
+        Ok(ExecutorConnection::Borrowed(&mut *self))
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::none()
     }
 }
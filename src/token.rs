@@ -0,0 +1,130 @@
+use crate::debug_println;
+use crate::error::{Error, Result};
+use crate::secret::Secret;
+use std::fmt;
+use std::str::FromStr;
+
+const PROXY_PREFIX: &str = "pxy.";
+
+/// Credentials of a proxy account, written as `pxy.<name>.<password>`. The
+/// account logs in to MySQL as user `pxy.<name>`.
+///
+/// Display writes the full token, password included, so that it round-trips
+/// through [`FromStr`]; Debug redacts the password.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyToken {
+    name: String,
+    password: Secret<String>,
+}
+
+impl ProxyToken {
+    pub fn new(name: &str, password: Secret<String>) -> Result<ProxyToken> {
+        if !ProxyToken::is_valid_part(name) {
+            return Err(Error::TokenMalformed(
+                "proxy account name must be letters and digits".to_string(),
+            ));
+        }
+        if !ProxyToken::is_valid_password(password.expose()) {
+            return Err(Error::TokenMalformed(
+                "proxy account password must be non-empty and contain no '.'".to_string(),
+            ));
+        }
+        Ok(ProxyToken {
+            name: name.to_string(),
+            password,
+        })
+    }
+
+    /// Reads the token from `MIRANDA_TOKEN`, or from the file named by
+    /// `MIRANDA_TOKEN_FILE`. Returns None if neither is set.
+    pub fn new_from_env() -> Result<Option<ProxyToken>> {
+        ProxyToken::new_from_env_with(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`ProxyToken::new_from_env`], reading variables through `var`.
+    pub fn new_from_env_with(var: impl Fn(&str) -> Option<String>) -> Result<Option<ProxyToken>> {
+        if let Some(token) = var("MIRANDA_TOKEN") {
+            debug_println!("[token] Loading proxy token from MIRANDA_TOKEN");
+            return token.parse().map(Some);
+        }
+        if let Some(path) = var("MIRANDA_TOKEN_FILE") {
+            return ProxyToken::new_from_file(&path).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Reads a file holding only the token. Surrounding whitespace is ignored.
+    pub fn new_from_file(path: &str) -> Result<ProxyToken> {
+        debug_println!("[token] Loading proxy token from {}", path);
+        let token = Secret::new(
+            std::fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("Error reading {}: {}", path, e)))?,
+        );
+        token.expose().trim().parse()
+    }
+
+    /// Returns true if `username` is the MySQL user of a proxy account.
+    pub fn is_proxy_user(username: &str) -> bool {
+        ProxyToken::account_name(username).is_some()
+    }
+
+    /// The `<name>` of a proxy account's MySQL user `pxy.<name>`.
+    pub fn account_name(username: &str) -> Option<&str> {
+        username.strip_prefix(PROXY_PREFIX)
+    }
+
+    pub(crate) fn is_valid_part(part: &str) -> bool {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    /// Passwords may hold any character except the '.' that separates the token's parts.
+    pub(crate) fn is_valid_password(password: &str) -> bool {
+        !password.is_empty() && !password.contains('.')
+    }
+
+    /// The MySQL user name, `pxy.<name>`.
+    pub fn username(&self) -> String {
+        format!("{}{}", PROXY_PREFIX, self.name)
+    }
+
+    pub fn password(&self) -> &Secret<String> {
+        &self.password
+    }
+}
+
+impl FromStr for ProxyToken {
+    type Err = Error;
+
+    fn from_str(token: &str) -> Result<ProxyToken> {
+        let rest = token
+            .strip_prefix(PROXY_PREFIX)
+            .ok_or_else(|| Error::TokenMalformed("proxy token must start with pxy.".to_string()))?;
+        match rest.split_once('.') {
+            Some((name, password)) => ProxyToken::new(name, Secret::from(password)),
+            None => Err(Error::TokenMalformed(
+                "expected pxy.<username>.<password>".to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProxyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}.{}",
+            PROXY_PREFIX,
+            self.name,
+            self.password.expose()
+        )
+    }
+}
+
+impl fmt::Debug for ProxyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyToken")
+            .field("username", &self.username())
+            .field("password", &self.password)
+            .finish()
+    }
+}